@@ -1,20 +1,40 @@
 #![no_main]
 #![no_std]
 
+use core::convert::Infallible;
+use core::pin::pin;
+
 use assign_resources::assign_resources;
-use common_lib::cli::{RootCommand, Shell, SubCommand};
-use common_lib::matrix::LedMatrix;
+use common_lib::cli::{
+    AsyncCommandHandler, CommandSpec, ParsedCommand, RawShell, ReplyChannel, Responder,
+    ShellCommand, ShellError, SubCommand, TaggedReplyChannel, TypedCommand, TypedReceiver,
+    TypedRequest, MAX_TEXT_LEN, QUEUE_DEPTH, REGISTRY_LEN,
+};
+use common_lib::framed::{write_reply, MuxTransport};
+use common_lib::matrix::{LedMatrix, MatrixDisplay, MatrixFrame};
+#[cfg(feature = "rtt")]
+use common_lib::rtt::{RttRx, RttTx};
+use common_lib::script::{Script, ScriptError, ScriptStore, MAX_SCRIPT_NAME};
 use common_lib::scroller::{ScrollDirection, Scroller, ScrollerError, MATRIX_SIZE};
-use common_lib::transport::{Transport, UartTransport};
+use common_lib::transport::{TransportWriter, UartWriter, MAX_LINE_LEN};
+use common_lib::uarte::{UarteRx, UarteTx};
 use defmt::{info, warn};
+#[cfg(all(feature = "rtt", feature = "defmt-rtt"))]
+compile_error!("`rtt` logs through rtt-target, so build it with --no-default-features");
+#[cfg(feature = "defmt-rtt")]
 use defmt_rtt as _;
 use embassy_executor::Spawner;
+use embassy_futures::join::join;
+use embassy_futures::select::{select, select3, Either, Either3};
 use embassy_nrf::gpio::{Level, Output, OutputDrive};
 use embassy_nrf::{bind_interrupts, peripherals, uarte};
-use embassy_sync::channel::Channel;
-use embassy_time::{Duration, Timer};
-use heapless::{String, Vec};
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::String;
 use panic_probe as _;
+#[cfg(feature = "rtt")]
+use rtt_target::{DownChannel, UpChannel};
 
 assign_resources! {
     matrix_pins: LedMatrixPins {
@@ -70,72 +90,273 @@ pub fn init_leds(matrix_pins: LedMatrixPins) -> LedMatrix<Output<'static>, MATRI
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
+    // defmt takes up channel 0, which probe-rs decodes; the shell gets its
+    // own pair of channels.
+    #[cfg(feature = "rtt")]
+    let channels = rtt_target::rtt_init! {
+        up: {
+            0: { size: 1024, name: "defmt" }
+            1: { size: 1024, name: "Shell" }
+        }
+        down: {
+            0: { size: 64, name: "Shell" }
+        }
+    };
+    #[cfg(feature = "rtt")]
+    rtt_target::set_defmt_channel(channels.up.0);
+
     info!("Starting...");
     let p = embassy_nrf::init(Default::default());
 
     let resources = split_resources!(p);
 
-    static SHELL: Shell = Shell::new();
+    static SHELL: Shell = Shell::with_handlers((Hello, Status));
+
+    // Register every command before any task runs, so the boot script and
+    // the first console lines find them.
+    static SCROLL: TypedCommand<Scroll> = TypedCommand::new();
+    static FRAME: TypedCommand<Frame> = TypedCommand::new();
+    static SCRIPT: TypedCommand<ScriptCommand> = TypedCommand::new();
+    let scrolls = SHELL.register_typed(&SCROLL).await.unwrap();
+    let frames = SHELL.register_typed(&FRAME).await.unwrap();
+    let script_requests = SHELL.register_typed(&SCRIPT).await.unwrap();
 
     spawner
-        .spawn(animate(resources.matrix_pins, &SHELL))
+        .spawn(animate(resources.matrix_pins, scrolls, frames))
         .unwrap();
     spawner
         .spawn(command_line(resources.uarte_resources, &SHELL))
         .unwrap();
-    spawner.spawn(shell_test_revc(&SHELL)).unwrap();
+    spawner.spawn(scripts(&SHELL, script_requests)).unwrap();
+    #[cfg(feature = "rtt")]
+    spawner
+        .spawn(rtt_console(channels.down.0, channels.up.1, &SHELL))
+        .unwrap();
 }
 
-#[embassy_executor::task]
-async fn shell_test_revc(shell: &'static Shell) {
-    static ROOT: RootCommand<0> = RootCommand {
-        root: "Hello",
-        sub: [],
-        channel: Channel::new(),
-    };
+/// Commands answered inline by the shell, without a task of their own.
+type Handlers = (Hello, Status);
+type Shell = RawShell<CriticalSectionRawMutex, MAX_LINE_LEN, QUEUE_DEPTH, REGISTRY_LEN, Handlers>;
 
-    let mut receiver = shell.register(&ROOT).await;
+struct Hello;
 
-    loop {
-        let out = receiver.get().await;
-        info!("{}", out.as_str());
+impl CommandSpec for Hello {
+    fn get_root(&self) -> &'static str {
+        "Hello"
+    }
+
+    fn get_description(&self) -> &'static str {
+        "Log a greeting"
+    }
+
+    fn get_children(&self) -> &[SubCommand] {
+        &[]
+    }
+}
+
+impl AsyncCommandHandler for Hello {
+    async fn handle(&self, command: ParsedCommand, reply: Responder) -> Result<(), ShellError> {
+        info!("{}", command.root);
+        reply.reply("Hello!").await;
+        Ok(())
+    }
+}
+
+struct Status;
+
+impl CommandSpec for Status {
+    fn get_root(&self) -> &'static str {
+        "status"
+    }
+
+    fn get_description(&self) -> &'static str {
+        "Show the time since start up"
+    }
+
+    fn get_children(&self) -> &[SubCommand] {
+        &[]
     }
 }
 
+impl AsyncCommandHandler for Status {
+    async fn handle(&self, _command: ParsedCommand, reply: Responder) -> Result<(), ShellError> {
+        reply
+            .reply_fmt(format_args!("uptime {}s", Instant::now().as_secs()))
+            .await;
+        Ok(())
+    }
+}
+
+/// Scroll text across the LED matrix
+#[derive(ShellCommand)]
+#[shell(backpressure = Reject)]
+enum Scroll {
+    /// Scroll text from right to left
+    Forward { text: String<MAX_TEXT_LEN> },
+    /// Scroll text from left to right
+    Back { text: String<MAX_TEXT_LEN> },
+}
+
+/// Show a still frame on the LED matrix
+#[derive(ShellCommand)]
+#[shell(backpressure = Reject)]
+enum Frame {
+    /// Show rows given as two hex digits each, the leftmost column in 0x10
+    Set { rows: String<{ 2 * MATRIX_SIZE }> },
+    /// Blank the matrix
+    Clear,
+}
+
 #[embassy_executor::task]
-async fn animate(matrix_pins: LedMatrixPins, shell: &'static Shell) {
+async fn animate(
+    matrix_pins: LedMatrixPins,
+    mut scrolls: TypedReceiver<'static, Scroll>,
+    mut frames: TypedReceiver<'static, Frame>,
+) {
     let mut matrix = init_leds(matrix_pins);
     let frame_time = Duration::from_millis(300);
-    let mut scroller = Scroller::new(&mut matrix);
-
-    static ROOT: RootCommand<1> = RootCommand {
-        root: "scroll",
-        sub: [SubCommand {
-            command: "forward",
-            args: 1,
-        }],
-        channel: Channel::new(),
-    };
 
-    let mut receiver = shell.register(&ROOT).await;
+    // A frame set with `frame set`, kept on the display until replaced.
+    let mut still: Option<MatrixFrame<MATRIX_SIZE>> = None;
 
     loop {
-        let command = receiver.get().await;
-        let commands: Vec<&str, 1> = Vec::from_iter(command.split_ascii_whitespace().skip(2));
+        let refresh = async {
+            match &still {
+                Some(frame) => loop {
+                    matrix.display_frame(frame).await;
+                },
+                None => core::future::pending::<Infallible>().await,
+            }
+        };
 
-        let out = scroller
-            .display_string(&commands[0], ScrollDirection::Left, frame_time)
-            .await;
-        match out {
-            Err(ScrollerError::UnsupportedCharacter(c)) => {
-                warn!("Unknown Character {}", c);
-                return;
+        match select3(scrolls.get(), frames.get(), refresh).await {
+            Either3::First(request) => {
+                still = None;
+                let (text, direction) = match &request.command {
+                    Scroll::Forward { text } => (text, ScrollDirection::Left),
+                    Scroll::Back { text } => (text, ScrollDirection::Right),
+                };
+
+                let mut scroller = Scroller::new(&mut matrix);
+                let out = scroller.display_string(text, direction, frame_time).await;
+                match out {
+                    Err(ScrollerError::UnsupportedCharacter(c)) => {
+                        warn!("Unknown Character {}", c);
+                        request
+                            .reply
+                            .error_fmt(format_args!("unsupported character '{}'", c))
+                            .await;
+                    }
+                    _ => request.reply.reply("OK").await,
+                }
             }
-            _ => (),
+            Either3::Second(request) => match request.command {
+                Frame::Set { rows } => match MatrixFrame::from_hex(&rows) {
+                    Some(frame) => {
+                        still = Some(frame);
+                        request.reply.reply("OK").await;
+                    }
+                    None => request.reply.error("invalid rows").await,
+                },
+                Frame::Clear => {
+                    still = None;
+                    request.reply.reply("OK").await;
+                }
+            },
+            Either3::Third(never) => match never {},
         }
     }
 }
 
+/// Scripts built into the firmware, by name. `boot` runs on start up.
+static SCRIPTS: [(&str, &str); 2] = [
+    ("boot", "Hello; scroll forward HI"),
+    // Scrolls are rejected while the queue is full, so pace them to the
+    // display.
+    (
+        "demo",
+        "repeat { scroll forward HELLO; sleep 10s; scroll back WORLD; sleep 10s }",
+    ),
+];
+
+/// Room for the built in scripts and a couple defined from the shell.
+type Scripts = ScriptStore<4, 128, 16>;
+
+/// Run stored command scripts
+#[derive(ShellCommand)]
+#[shell(root = "script", backpressure = Reject)]
+enum ScriptCommand {
+    /// Store a script, quoted, replacing any of the same name
+    Define {
+        name: String<MAX_SCRIPT_NAME>,
+        text: String<128>,
+    },
+    /// Run a stored script, stopping any running one
+    Run { name: String<MAX_SCRIPT_NAME> },
+    /// Stop the running script
+    Stop,
+}
+
+#[embassy_executor::task]
+async fn scripts(shell: &'static Shell, mut receiver: TypedReceiver<'static, ScriptCommand>) {
+    let mut store = Scripts::new();
+    for (name, text) in SCRIPTS {
+        if let Err(e) = store.define(name, text) {
+            warn!("script {}: {}", name, defmt::Display2Format(&e));
+        }
+    }
+    let mut running = store.get("boot").cloned();
+
+    loop {
+        let Some(script) = running.take() else {
+            running = script_request(&mut store, receiver.get().await).await;
+            continue;
+        };
+
+        // Definitions are taken while the script runs; `run` and `stop`
+        // replace or end it.
+        let mut run = pin!(script.run(shell, Responder::detached()));
+        loop {
+            match select(run.as_mut(), receiver.get()).await {
+                Either::First(Err(e)) => warn!("script: {}", defmt::Display2Format(&e)),
+                Either::First(Ok(())) => {}
+                Either::Second(request) => {
+                    let stop = matches!(request.command, ScriptCommand::Stop);
+                    running = script_request(&mut store, request).await;
+                    if !stop && running.is_none() {
+                        continue;
+                    }
+                }
+            }
+            break;
+        }
+    }
+}
+
+/// Answers a `script` request, returning the script it starts, if any.
+async fn script_request(
+    store: &mut Scripts,
+    request: TypedRequest<ScriptCommand>,
+) -> Option<Script<128, 16>> {
+    let TypedRequest { command, reply } = request;
+    match command {
+        ScriptCommand::Define { name, text } => match store.define(&name, &text) {
+            Ok(()) => reply.reply("OK").await,
+            Err(e) => reply.error(e).await,
+        },
+        ScriptCommand::Run { name } => match store.get(&name) {
+            Some(script) => {
+                info!("running script {}", name.as_str());
+                reply.reply("OK").await;
+                return Some(script.clone());
+            }
+            None => reply.error(ScriptError::Unknown).await,
+        },
+        ScriptCommand::Stop => reply.reply("OK").await,
+    }
+    None
+}
+
 #[embassy_executor::task]
 async fn command_line(uarte_resources: UartResources, shell: &'static Shell) {
     let rx = uarte_resources.rx;
@@ -150,17 +371,74 @@ async fn command_line(uarte_resources: UartResources, shell: &'static Shell) {
     config.baudrate = uarte::Baudrate::BAUD115200;
     let uarte_device = uarte::Uarte::new(uarte, Irqs, rx, tx, config);
 
-    let (_, uarte_rx) = uarte_device.split_with_idle(timer, ppi1, ppi2);
+    let (uarte_tx, uarte_rx) = uarte_device.split_with_idle(timer, ppi1, ppi2);
 
-    let mut transport = UartTransport::new(uarte_rx);
+    // Replies to typed lines, and to framed requests.
+    static REPLIES: ReplyChannel = ReplyChannel::new();
+    static TAGGED: TaggedReplyChannel = TaggedReplyChannel::new();
 
-    loop {
-        if let Some(command) = transport.next_line().await.unwrap() {
-            warn!("{:?}", command.as_str());
-            shell.send(command).await.unwrap();
-            Timer::after(Duration::from_secs(1)).await
-        }
-    }
+    serve(uarte_rx, uarte_tx, shell, &REPLIES, &TAGGED).await;
 
     // uart_re_test(uarte_rx).await;
 }
+
+#[cfg(feature = "rtt")]
+#[embassy_executor::task]
+async fn rtt_console(down: DownChannel, up: UpChannel, shell: &'static Shell) {
+    static REPLIES: ReplyChannel = ReplyChannel::new();
+    static TAGGED: TaggedReplyChannel = TaggedReplyChannel::new();
+
+    serve(RttRx::new(down), RttTx::new(up), shell, &REPLIES, &TAGGED).await;
+}
+
+/// Runs the shell for a terminal, or a program sending frames, on `rx`
+/// and `tx`.
+async fn serve(
+    rx: impl UarteRx,
+    tx: impl UarteTx,
+    shell: &'static Shell,
+    replies: &'static ReplyChannel,
+    tagged: &'static TaggedReplyChannel,
+) {
+    // Echo and command replies share the transmitter.
+    let tx: Mutex<NoopRawMutex, _> = Mutex::new(tx);
+
+    let mut transport: MuxTransport<_, _, _, 16> =
+        MuxTransport::new(rx, &tx, shell, replies, tagged);
+
+    let input = async {
+        loop {
+            match transport.next_request().await {
+                Ok(Some((command, reply))) => {
+                    warn!("{:?}", command.as_str());
+                    // Failures are reported through the responder by the shell.
+                    let _ = shell.send_with_reply(&command, reply).await;
+                    Timer::after(Duration::from_secs(1)).await
+                }
+                Ok(None) => {}
+                // Line errors are recovered inside the transport; anything
+                // else is retried after a pause rather than ending the task.
+                Err(e) => {
+                    warn!("console: {}", defmt::Display2Format(&e));
+                    Timer::after(Duration::from_millis(100)).await
+                }
+            }
+        }
+    };
+
+    let output = async {
+        loop {
+            match select(replies.receive(), tagged.receive()).await {
+                Either::First(line) => {
+                    let mut tx = tx.lock().await;
+                    let _ = UartWriter::new(&mut *tx).write_line(&line).await;
+                }
+                Either::Second(reply) => {
+                    let _ = write_reply(&mut *tx.lock().await, &reply).await;
+                }
+            }
+        }
+    };
+
+    join(input, output).await;
+}
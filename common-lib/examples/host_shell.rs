@@ -0,0 +1,61 @@
+//! The shell on a pseudo-terminal, to try it without a board:
+//!
+//! ```text
+//! cargo run -p common-lib --features host --example host_shell
+//! picocom /dev/pts/N
+//! ```
+
+use common_lib::cli::{ReplyChannel, Responder, RootCommand, Shell};
+use common_lib::host::Pty;
+use common_lib::transport::{InteractiveTransport, Transport, TransportWriter, UartWriter};
+use embassy_executor::Spawner;
+use embassy_futures::join::join3;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex;
+
+static SHELL: Shell = Shell::new();
+static PING: RootCommand<0> = RootCommand::new("ping", "Reply pong", []);
+static REPLIES: ReplyChannel = ReplyChannel::new();
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    pretty_env_logger::init();
+    // Too big for the executor's task arena, so kept on the heap.
+    Box::pin(run()).await;
+}
+
+async fn run() {
+    let pty = Pty::open().unwrap();
+    println!("shell on {}", pty.path().display());
+    let (rx, tx) = pty.split();
+
+    // Echo and command replies share the transmitter.
+    let tx: Mutex<NoopRawMutex, _> = Mutex::new(tx);
+    let mut transport: InteractiveTransport<_, _, _, 16> =
+        InteractiveTransport::new(rx, &tx, &SHELL);
+    let mut ping = SHELL.register(&PING).await.unwrap();
+
+    let input = async {
+        loop {
+            if let Some(line) = transport.next_line().await.unwrap() {
+                let _ = SHELL.send_with_reply(&line, Responder::new(&REPLIES)).await;
+            }
+        }
+    };
+
+    let pong = async {
+        loop {
+            ping.get().await.reply.reply("pong").await;
+        }
+    };
+
+    let output = async {
+        loop {
+            let line = REPLIES.receive().await;
+            let mut tx = tx.lock().await;
+            let _ = UartWriter::new(&mut *tx).write_line(&line).await;
+        }
+    };
+
+    join3(input, pong, output).await;
+}
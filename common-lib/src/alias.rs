@@ -0,0 +1,324 @@
+use heapless::{String, Vec};
+use thiserror::Error;
+
+use crate::tokenizer::split_unquoted;
+
+/// How deeply expansions may nest, which stops aliases that refer to each
+/// other in a loop.
+pub const MAX_EXPANSIONS: usize = 8;
+/// Parameters a macro body may refer to, as `$1` to `$9`.
+pub const MAX_MACRO_ARGS: usize = 9;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum AliasError {
+    #[error("alias store is full")]
+    StoreFull,
+    #[error("alias expansion too long")]
+    TooLong,
+    #[error("alias expansion too deep")]
+    TooDeep,
+    #[error("unknown alias")]
+    Unknown,
+    #[error("expected {expected} arguments, got {got}")]
+    TooFewArgs { expected: usize, got: usize },
+    #[error("too many arguments")]
+    TooManyArgs,
+}
+
+/// How a stored expansion replaces the command that names it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    /// The expansion replaces the first word, and the rest of the command
+    /// is appended.
+    Alias,
+    /// The expansion replaces the whole command, with `$1` to `$9` replaced
+    /// by the arguments after the name.
+    Macro,
+}
+
+struct Alias<const L: usize> {
+    kind: Kind,
+    name: String<L>,
+    expansion: String<L>,
+}
+
+/// Up to `N` named aliases and macros of at most `L` bytes each.
+///
+/// An expansion may itself hold several `;`-separated commands, and refer to
+/// further aliases and macros.
+pub struct AliasStore<const N: usize, const L: usize> {
+    aliases: Vec<Alias<L>, N>,
+}
+
+impl<const N: usize, const L: usize> Default for AliasStore<N, L> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize, const L: usize> AliasStore<N, L> {
+    pub const fn new() -> Self {
+        Self {
+            aliases: Vec::new(),
+        }
+    }
+
+    /// Adds or replaces the alias or macro `name`.
+    pub fn define(&mut self, kind: Kind, name: &str, expansion: &str) -> Result<(), AliasError> {
+        let expansion = String::try_from(expansion).map_err(|_| AliasError::TooLong)?;
+        if let Some(alias) = self.aliases.iter_mut().find(|a| a.name == name) {
+            alias.kind = kind;
+            alias.expansion = expansion;
+            return Ok(());
+        }
+        let name = String::try_from(name).map_err(|_| AliasError::TooLong)?;
+        self.aliases
+            .push(Alias {
+                kind,
+                name,
+                expansion,
+            })
+            .map_err(|_| AliasError::StoreFull)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<(), AliasError> {
+        let index = self
+            .aliases
+            .iter()
+            .position(|a| a.name == name)
+            .ok_or(AliasError::Unknown)?;
+        self.aliases.remove(index);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<(Kind, &str)> {
+        self.aliases
+            .iter()
+            .find(|a| a.name == name)
+            .map(|a| (a.kind, a.expansion.as_str()))
+    }
+
+    /// The defined aliases and macros as `(kind, name, expansion)`, oldest
+    /// first.
+    pub fn iter(&self) -> impl Iterator<Item = (Kind, &str, &str)> {
+        self.aliases
+            .iter()
+            .map(|a| (a.kind, a.name.as_str(), a.expansion.as_str()))
+    }
+
+    /// Appends `command` to `out` with its aliases and macros expanded,
+    /// after a `;` if `out` already holds commands. Commands the expansions
+    /// bring in are expanded in turn, up to [`MAX_EXPANSIONS`] deep.
+    pub fn expand<const LINE: usize>(
+        &self,
+        command: &str,
+        out: &mut String<LINE>,
+    ) -> Result<(), AliasError> {
+        let command = command.trim();
+        if command.is_empty() {
+            return Ok(());
+        }
+        if !out.is_empty() {
+            out.push(';').map_err(|_| AliasError::TooLong)?;
+        }
+        let mut start = out.len();
+        out.push_str(command).map_err(|_| AliasError::TooLong)?;
+
+        // The expansions around `start`, innermost last, each kept as how far
+        // its end is from the end of `out`, which edits before it don't move.
+        let mut nesting: Vec<usize, MAX_EXPANSIONS> = Vec::new();
+        while start < out.len() {
+            while nesting
+                .last()
+                .is_some_and(|&tail| out.len() - tail <= start)
+            {
+                nesting.pop();
+            }
+            let Some(piece) = split_unquoted(&out[start..], |c| c == ';').next() else {
+                break;
+            };
+            let end = start + piece.text.len();
+            let (word, rest) = split_word(piece.text.trim());
+            let Some((kind, expansion)) = self.get(word) else {
+                // Past the `;` that ended the piece, if any.
+                start = end + piece.separator.map_or(0, char::len_utf8);
+                continue;
+            };
+
+            nesting
+                .push(out.len() - end)
+                .map_err(|_| AliasError::TooDeep)?;
+            // The new piece is looked at again, as it may start with an alias.
+            let mut expanded: String<LINE> = String::new();
+            let push = |expanded: &mut String<LINE>, text: &str| {
+                expanded.push_str(text).map_err(|_| AliasError::TooLong)
+            };
+            push(&mut expanded, &out[..start])?;
+            match kind {
+                Kind::Alias => {
+                    push(&mut expanded, expansion)?;
+                    if !rest.is_empty() {
+                        push(&mut expanded, " ")?;
+                        push(&mut expanded, rest)?;
+                    }
+                }
+                Kind::Macro => substitute(&mut expanded, expansion, rest)?,
+            }
+            push(&mut expanded, &out[end..])?;
+            *out = expanded;
+        }
+        Ok(())
+    }
+}
+
+/// Splits the first word off `text`.
+pub(crate) fn split_word(text: &str) -> (&str, &str) {
+    text.split_once(|c: char| c.is_ascii_whitespace())
+        .map_or((text, ""), |(word, rest)| (word, rest.trim_start()))
+}
+
+/// Appends `body` to `out`, replacing `$1` to `$9` with the words of `args`.
+/// Quoted words are passed on with their quotes.
+fn substitute<const LINE: usize>(
+    out: &mut String<LINE>,
+    body: &str,
+    args: &str,
+) -> Result<(), AliasError> {
+    let mut words: Vec<&str, MAX_MACRO_ARGS> = Vec::new();
+    for piece in split_unquoted(args, |c| c.is_ascii_whitespace()) {
+        if !piece.text.is_empty() {
+            words
+                .push(piece.text)
+                .map_err(|_| AliasError::TooManyArgs)?;
+        }
+    }
+
+    let mut expected = 0;
+    let mut chars = body.chars().peekable();
+    while let Some(c) = chars.next() {
+        let index = match (c, chars.peek().and_then(|next| next.to_digit(10))) {
+            ('$', Some(digit @ 1..=9)) => digit as usize,
+            _ => {
+                out.push(c).map_err(|_| AliasError::TooLong)?;
+                continue;
+            }
+        };
+        chars.next();
+        expected = expected.max(index);
+        if let Some(word) = words.get(index - 1) {
+            out.push_str(word).map_err(|_| AliasError::TooLong)?;
+        }
+    }
+
+    if words.len() < expected {
+        return Err(AliasError::TooFewArgs {
+            expected,
+            got: words.len(),
+        });
+    }
+    if words.len() > expected {
+        return Err(AliasError::TooManyArgs);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn expand(store: &AliasStore<4, 32>, line: &str) -> Result<std::string::String, AliasError> {
+        let mut out: String<64> = String::new();
+        for command in line.split(';') {
+            store.expand(command, &mut out)?;
+        }
+        Ok(out.as_str().to_owned())
+    }
+
+    #[test]
+    fn test_alias_define_and_remove() {
+        let mut store: AliasStore<2, 32> = AliasStore::new();
+        store
+            .define(Kind::Alias, "hi", "scroll forward HI")
+            .unwrap();
+        store.define(Kind::Macro, "yo", "Hello").unwrap();
+        assert_eq!(
+            store.define(Kind::Alias, "bye", "x"),
+            Err(AliasError::StoreFull)
+        );
+
+        store
+            .define(Kind::Alias, "hi", "scroll forward HELLO")
+            .unwrap();
+        assert_eq!(store.get("hi"), Some((Kind::Alias, "scroll forward HELLO")));
+
+        store.remove("hi").unwrap();
+        assert_eq!(store.remove("hi"), Err(AliasError::Unknown));
+        assert_eq!(
+            store.iter().collect::<std::vec::Vec<_>>(),
+            [(Kind::Macro, "yo", "Hello")]
+        );
+    }
+
+    #[test]
+    fn test_alias_expand() {
+        let mut store: AliasStore<4, 32> = AliasStore::new();
+        store.define(Kind::Alias, "hi", "scroll forward").unwrap();
+        store.define(Kind::Alias, "both", "hi A; Hello").unwrap();
+
+        assert_eq!(expand(&store, "hi 'A B'").unwrap(), "scroll forward 'A B'");
+        assert_eq!(
+            expand(&store, "both; Hello world").unwrap(),
+            "scroll forward A; Hello;Hello world"
+        );
+        assert_eq!(expand(&store, " ; ;").unwrap(), "");
+        assert_eq!(expand(&store, "'hi' x").unwrap(), "'hi' x");
+    }
+
+    #[test]
+    fn test_macro_expand() {
+        let mut store: AliasStore<4, 32> = AliasStore::new();
+        store.define(Kind::Alias, "hi", "scroll forward").unwrap();
+        store.define(Kind::Macro, "twice", "hi $1; hi $1").unwrap();
+        store.define(Kind::Macro, "swap", "say $2 $1").unwrap();
+
+        assert_eq!(
+            expand(&store, "twice 'A B'").unwrap(),
+            "scroll forward 'A B';scroll forward 'A B'"
+        );
+        assert_eq!(expand(&store, "swap a  b").unwrap(), "say b a");
+        assert_eq!(
+            expand(&store, "swap a"),
+            Err(AliasError::TooFewArgs {
+                expected: 2,
+                got: 1
+            })
+        );
+        assert_eq!(expand(&store, "swap a b c"), Err(AliasError::TooManyArgs));
+    }
+
+    #[test]
+    fn test_alias_expand_errors() {
+        let mut store: AliasStore<4, 32> = AliasStore::new();
+        store.define(Kind::Alias, "loop", "loop").unwrap();
+        store.define(Kind::Alias, "many", "a; b; c").unwrap();
+        store
+            .define(Kind::Alias, "long", "scroll forward ABCDEFGHIJKLMNOP")
+            .unwrap();
+
+        assert_eq!(expand(&store, "loop"), Err(AliasError::TooDeep));
+        // Only nesting counts, not how many aliases a command uses.
+        let mut out: String<128> = String::new();
+        store
+            .expand(
+                "many; many; many; many; many; many; many; many; many",
+                &mut out,
+            )
+            .unwrap();
+        assert_eq!(out.as_str(), ["a; b; c"; 9].join(";"));
+        assert_eq!(expand(&store, "many; many").unwrap(), "a; b; c;a; b; c");
+        assert_eq!(
+            expand(&store, &format!("long {}", "Q".repeat(40))),
+            Err(AliasError::TooLong)
+        );
+    }
+}
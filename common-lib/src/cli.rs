@@ -1,302 +1,3714 @@
-use core::str::FromStr;
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+use core::future::poll_fn;
+use core::marker::PhantomData;
+use core::task::{Context, Poll};
 
-use embassy_sync::mutex::Mutex;
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::waitqueue::{MultiWakerRegistration, WakerRegistration};
+use embassy_time::Duration;
 use heapless::String;
 use heapless::Vec;
+use serde::Serialize;
 
+use crate::alias::{split_word, AliasError, AliasStore, Kind};
+use crate::line_editor::{Completer, MAX_COMPLETIONS};
 use crate::prelude::*;
+use crate::tokenizer::{split_unquoted, tokenize, Piece, TokenizeError, Tokens};
+use crate::transport::MAX_LINE_LEN;
 
 use thiserror::Error;
 
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-use embassy_sync::channel::Channel;
+/// The bound on a shell's mutex, for derived commands to name.
+pub use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::channel::{Channel, TryReceiveError, TrySendError};
 
-pub trait Command: Send + Sync {
+pub const MAX_TOKENS: usize = 10;
+pub const MAX_ARGS: usize = MAX_TOKENS - 2;
+pub const MAX_DEPTH: usize = MAX_TOKENS - 1;
+pub const HELP_BUF_LEN: usize = 1024;
+pub const QUEUE_DEPTH: usize = 5;
+pub const REPLY_QUEUE_LEN: usize = 8;
+pub const REGISTRY_LEN: usize = 50;
+pub const MAX_ALIASES: usize = 16;
+pub const MAX_ALIAS_LEN: usize = 128;
+/// Senders that can wait on full queues before they are woken to check for
+/// unregistration more often than needed.
+const BLOCKED_SENDERS: usize = 4;
+
+/// Longest reply line, matching what a framed reply carries.
+pub use shell_protocol::MAX_REPLY_LEN;
+/// A size for free text arguments, matching what a framed request carries.
+pub use shell_protocol::MAX_TEXT_LEN;
+
+pub type ReplyLine = String<MAX_REPLY_LEN>;
+/// Reply lines on their way to a transport, which also keep the
+/// transport's [`OutputMode`]. Responders travel with requests to other
+/// tasks, so the mutex must be `Sync`.
+pub type ReplyChannel<M = CriticalSectionRawMutex, const N: usize = REPLY_QUEUE_LEN> =
+    RawReplyChannel<M, ReplyLine, N>;
+/// Replies for a transport that encodes them itself, as [`ReplyChannel`].
+pub type TaggedReplyChannel<M = CriticalSectionRawMutex, const N: usize = REPLY_QUEUE_LEN> =
+    RawReplyChannel<M, TaggedReply, N>;
+
+pub const HELP_ROOT: &str = "help";
+pub const ALIAS_ROOT: &str = "alias";
+pub const UNALIAS_ROOT: &str = "unalias";
+pub const MACRO_ROOT: &str = "macro";
+pub const MODE_ROOT: &str = "mode";
+const BUILTINS: [&str; 5] = [HELP_ROOT, ALIAS_ROOT, MACRO_ROOT, UNALIAS_ROOT, MODE_ROOT];
+/// Starts a request's correlation id, as in `#42 led status`.
+pub const ID_PREFIX: char = '#';
+
+/// The name, description and subcommand tree of a root command, whichever
+/// way its requests are handled.
+pub trait CommandSpec {
     fn get_root(&self) -> &'static str;
-    fn get_channel(&self) -> &Channel<CriticalSectionRawMutex, String<256>, 5>;
-    fn get_sub_commands(&self) -> &[SubCommand];
+    fn get_description(&self) -> &'static str;
+    fn get_children(&self) -> &[SubCommand];
 }
 
-pub struct SubCommand {
-    pub command: &'static str,
-    pub args: usize,
+/// A root command a [`RawShell`] dispatches to, queueing requests for lines
+/// of up to `LINE` bytes on a channel guarded by `M`.
+pub trait Command<
+    M: RawMutex + 'static = CriticalSectionRawMutex,
+    const DEPTH: usize = QUEUE_DEPTH,
+    const LINE: usize = MAX_LINE_LEN,
+>: CommandSpec
+{
+    fn get_channel(&self) -> &Channel<M, Request<LINE>, DEPTH>;
+}
+
+/// A root command the shell runs inline as it dispatches, rather than
+/// queueing it for a task of its own.
+///
+/// Suits quick commands such as status queries and toggles: there is no
+/// task or channel to pay for, but the sender waits while it runs and its
+/// error is returned from the send.
+pub trait AsyncCommandHandler<const LINE: usize = MAX_LINE_LEN>: CommandSpec {
+    async fn handle(
+        &self,
+        command: ParsedCommand<LINE>,
+        reply: Responder,
+    ) -> Result<(), ShellError>;
+}
+
+/// The inline handlers a shell is built with: `()` for none, or a tuple of
+/// [`AsyncCommandHandler`]s.
+pub trait CommandHandlers<const LINE: usize = MAX_LINE_LEN> {
+    /// The handler at `index`, in tuple order.
+    fn get(&self, index: usize) -> Option<&dyn CommandSpec>;
+
+    async fn handle(
+        &self,
+        index: usize,
+        command: ParsedCommand<LINE>,
+        reply: Responder,
+    ) -> Result<(), ShellError>;
+}
+
+impl<const LINE: usize> CommandHandlers<LINE> for () {
+    fn get(&self, _index: usize) -> Option<&dyn CommandSpec> {
+        None
+    }
+
+    async fn handle(
+        &self,
+        _index: usize,
+        _command: ParsedCommand<LINE>,
+        _reply: Responder,
+    ) -> Result<(), ShellError> {
+        Err(ShellError::UnknownCommand)
+    }
+}
+
+macro_rules! tuple_handlers {
+    ($($handler:ident $index:tt),*) => {
+        impl<const LINE: usize, $($handler: AsyncCommandHandler<LINE>),*> CommandHandlers<LINE>
+            for ($($handler,)*)
+        {
+            fn get(&self, index: usize) -> Option<&dyn CommandSpec> {
+                match index {
+                    $($index => Some(&self.$index),)*
+                    _ => None,
+                }
+            }
+
+            async fn handle(
+                &self,
+                index: usize,
+                command: ParsedCommand<LINE>,
+                reply: Responder,
+            ) -> Result<(), ShellError> {
+                match index {
+                    $($index => self.$index.handle(command, reply).await,)*
+                    _ => Err(ShellError::UnknownCommand),
+                }
+            }
+        }
+    };
+}
+
+tuple_handlers!(A 0);
+tuple_handlers!(A 0, B 1);
+tuple_handlers!(A 0, B 1, C 2);
+tuple_handlers!(A 0, B 1, C 2, D 3);
+tuple_handlers!(A 0, B 1, C 2, D 3, E 4);
+tuple_handlers!(A 0, B 1, C 2, D 3, E 4, F 5);
+tuple_handlers!(A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+tuple_handlers!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+
+/// What dispatch does when a command's request queue is full, chosen when
+/// the command is registered.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Backpressure {
+    /// Wait for the handler to make room.
+    #[default]
+    Block,
+    /// Discard the new request, answering it with [`ShellError::Dropped`].
+    /// The rest of the line still runs.
+    DropNewest,
+    /// Discard the oldest queued request to make room for the new one,
+    /// answering the old one with [`ShellError::Dropped`].
+    DropOldest,
+    /// Fail the send with [`ShellError::Busy`].
+    Reject,
+}
+
+/// The type an argument is validated and parsed as.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArgKind {
+    /// Signed integer within an inclusive range.
+    Int { min: i32, max: i32 },
+    /// `true`/`false`, `on`/`off` or `1`/`0`.
+    Bool,
+    /// One of a fixed set of keywords.
+    Keyword(&'static [&'static str]),
+    /// Any single token of up to `max_len` bytes.
+    Str { max_len: usize },
+    /// Milliseconds, with an optional `ms` or `s` suffix.
+    Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Arg {
+    pub name: &'static str,
+    pub kind: ArgKind,
+}
+
+impl Arg {
+    pub const fn int(name: &'static str, min: i32, max: i32) -> Self {
+        Self {
+            name,
+            kind: ArgKind::Int { min, max },
+        }
+    }
+
+    pub const fn bool(name: &'static str) -> Self {
+        Self {
+            name,
+            kind: ArgKind::Bool,
+        }
+    }
+
+    pub const fn keyword(name: &'static str, keywords: &'static [&'static str]) -> Self {
+        Self {
+            name,
+            kind: ArgKind::Keyword(keywords),
+        }
+    }
+
+    pub const fn str(name: &'static str) -> Self {
+        Self {
+            name,
+            kind: ArgKind::Str {
+                max_len: usize::MAX,
+            },
+        }
+    }
+
+    pub const fn duration(name: &'static str) -> Self {
+        Self {
+            name,
+            kind: ArgKind::Duration,
+        }
+    }
+
+    pub(crate) fn parse<'t>(&self, token: &'t str) -> Result<ArgValue<'t>, ShellError> {
+        let invalid = ShellError::InvalidArg(self.name);
+        match self.kind {
+            ArgKind::Int { min, max } => {
+                let value = token.parse::<i32>().map_err(|_| invalid.clone())?;
+                if value < min || value > max {
+                    return Err(invalid);
+                }
+                Ok(ArgValue::Int(value))
+            }
+            ArgKind::Bool => match token {
+                "true" | "on" | "1" => Ok(ArgValue::Bool(true)),
+                "false" | "off" | "0" => Ok(ArgValue::Bool(false)),
+                _ => Err(invalid),
+            },
+            ArgKind::Keyword(keywords) => keywords
+                .iter()
+                .find(|&&keyword| keyword == token)
+                .map(|&keyword| ArgValue::Keyword(keyword))
+                .ok_or(invalid),
+            ArgKind::Str { max_len } if token.len() > max_len => Err(invalid),
+            ArgKind::Str { .. } => Ok(ArgValue::Str(token)),
+            ArgKind::Duration => {
+                let (number, scale) = if let Some(ms) = token.strip_suffix("ms") {
+                    (ms, 1)
+                } else if let Some(s) = token.strip_suffix('s') {
+                    (s, 1000)
+                } else {
+                    (token, 1)
+                };
+                let value = number.parse::<u64>().map_err(|_| invalid.clone())?;
+                let millis = value.checked_mul(scale).ok_or(invalid)?;
+                Ok(ArgValue::Duration(Duration::from_millis(millis)))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Arg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<{}:", self.name)?;
+        match self.kind {
+            ArgKind::Int { min, max } => write!(f, "int {}..={}", min, max)?,
+            ArgKind::Bool => f.write_str("bool")?,
+            ArgKind::Keyword(keywords) => {
+                for (i, keyword) in keywords.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char('|')?;
+                    }
+                    f.write_str(keyword)?;
+                }
+            }
+            ArgKind::Str { .. } => f.write_str("str")?,
+            ArgKind::Duration => f.write_str("duration")?,
+        }
+        f.write_char('>')
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArgValue<'a> {
+    Int(i32),
+    Bool(bool),
+    Keyword(&'static str),
+    Str(&'a str),
+    Duration(Duration),
+}
+
+impl<'a> ArgValue<'a> {
+    pub fn as_int(&self) -> Option<i32> {
+        match self {
+            ArgValue::Int(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            ArgValue::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_keyword(&self) -> Option<&'static str> {
+        match self {
+            ArgValue::Keyword(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&'a str> {
+        match self {
+            ArgValue::Str(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_duration(&self) -> Option<Duration> {
+        match self {
+            ArgValue::Duration(value) => Some(*value),
+            _ => None,
+        }
+    }
 }
 
-pub struct RootCommand<const N: usize> {
+/// A command line of up to `LINE` bytes that has been matched against a
+/// registered [`Command`] and had its arguments validated.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParsedCommand<const LINE: usize = MAX_LINE_LEN> {
     pub root: &'static str,
-    pub sub: [SubCommand; N],
-    pub channel: Channel<CriticalSectionRawMutex, String<256>, 5>,
+    /// The subcommands walked below the root, outermost first.
+    pub path: Vec<&'static str, MAX_DEPTH>,
+    /// How the arguments were validated, and so how they are read back.
+    specs: &'static [Arg],
+    args: Tokens<LINE, MAX_ARGS>,
+}
+
+impl<const LINE: usize> ParsedCommand<LINE> {
+    pub fn arg(&self, index: usize) -> Option<ArgValue<'_>> {
+        self.specs.get(index)?.parse(self.args.get(index)?).ok()
+    }
+
+    pub fn args(&self) -> impl Iterator<Item = ArgValue<'_>> {
+        (0..self.args.len()).map_while(|index| self.arg(index))
+    }
+
+    /// Converts argument `index` into a typed field called `name`.
+    pub fn field<T: FromArg>(&self, index: usize, name: &'static str) -> Result<T, ShellError> {
+        self.arg(index)
+            .as_ref()
+            .and_then(T::from_arg)
+            .ok_or(ShellError::InvalidArg(name))
+    }
+}
+
+/// A field type that a parsed argument can be converted into.
+pub trait FromArg: Sized {
+    /// How the argument is validated when no kind is given explicitly.
+    const KIND: ArgKind;
+
+    fn from_arg(value: &ArgValue<'_>) -> Option<Self>;
+}
+
+macro_rules! int_from_arg {
+    ($($ty:ty),*) => {
+        $(
+            impl FromArg for $ty {
+                const KIND: ArgKind = ArgKind::Int {
+                    min: if (<$ty>::MIN as i64) < i32::MIN as i64 {
+                        i32::MIN
+                    } else {
+                        <$ty>::MIN as i32
+                    },
+                    max: if (<$ty>::MAX as u64) > i32::MAX as u64 {
+                        i32::MAX
+                    } else {
+                        <$ty>::MAX as i32
+                    },
+                };
+
+                fn from_arg(value: &ArgValue<'_>) -> Option<Self> {
+                    value.as_int()?.try_into().ok()
+                }
+            }
+        )*
+    };
+}
+
+int_from_arg!(i8, i16, i32, i64, u8, u16, u32, u64, usize);
+
+impl FromArg for bool {
+    const KIND: ArgKind = ArgKind::Bool;
+
+    fn from_arg(value: &ArgValue<'_>) -> Option<Self> {
+        value.as_bool()
+    }
+}
+
+impl FromArg for Duration {
+    const KIND: ArgKind = ArgKind::Duration;
+
+    fn from_arg(value: &ArgValue<'_>) -> Option<Self> {
+        value.as_duration()
+    }
+}
+
+/// Longer arguments are rejected as the line is dispatched, rather than
+/// when the field is converted.
+impl<const N: usize> FromArg for String<N> {
+    const KIND: ArgKind = ArgKind::Str { max_len: N };
+
+    fn from_arg(value: &ArgValue<'_>) -> Option<Self> {
+        value.as_str()?.try_into().ok()
+    }
+}
+
+/// Keyword fields. The keywords themselves come from the field's
+/// `#[shell(keywords(..))]` attribute, which the derive requires; the
+/// default kind accepts nothing.
+impl FromArg for &'static str {
+    const KIND: ArgKind = ArgKind::Keyword(&[]);
+
+    fn from_arg(value: &ArgValue<'_>) -> Option<Self> {
+        value.as_keyword()
+    }
+}
+
+/// A command whose subcommands are the variants of a type, usually
+/// implemented with `#[derive(ShellCommand)]`. Its requests are queued on
+/// a [`RawTypedCommand`].
+pub trait ShellCommand<
+    M: RawMutex + 'static = CriticalSectionRawMutex,
+    const DEPTH: usize = QUEUE_DEPTH,
+    const LINE: usize = MAX_LINE_LEN,
+>: Sized
+{
+    const ROOT: &'static str;
+    const DESCRIPTION: &'static str;
+    const CHILDREN: &'static [SubCommand];
+    /// How the command is registered by [`RawShell::register_typed`].
+    const BACKPRESSURE: Backpressure = Backpressure::Block;
+
+    fn from_parsed(command: &ParsedCommand<LINE>) -> Result<Self, ShellError>;
 }
 
-impl<const N: usize> RootCommand<N> {
-    pub const fn new(root: &'static str, sub: [SubCommand; N]) -> Self {
+/// The queue of a [`ShellCommand`], kept in a `static` for
+/// [`RawShell::register_typed`].
+pub struct RawTypedCommand<
+    T,
+    M: RawMutex + 'static,
+    const DEPTH: usize,
+    const LINE: usize = MAX_LINE_LEN,
+> {
+    pub channel: Channel<M, Request<LINE>, DEPTH>,
+    command: PhantomData<fn() -> T>,
+}
+
+pub type TypedCommand<T> = RawTypedCommand<T, CriticalSectionRawMutex, QUEUE_DEPTH>;
+
+impl<T, M: RawMutex + 'static, const DEPTH: usize, const LINE: usize>
+    RawTypedCommand<T, M, DEPTH, LINE>
+{
+    pub const fn new() -> Self {
         Self {
-            root,
-            sub,
             channel: Channel::new(),
+            command: PhantomData,
+        }
+    }
+}
+
+impl<T, M: RawMutex + 'static, const DEPTH: usize, const LINE: usize> Default
+    for RawTypedCommand<T, M, DEPTH, LINE>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<
+        T: ShellCommand<M, DEPTH, LINE>,
+        M: RawMutex + 'static,
+        const DEPTH: usize,
+        const LINE: usize,
+    > CommandSpec for RawTypedCommand<T, M, DEPTH, LINE>
+{
+    fn get_root(&self) -> &'static str {
+        T::ROOT
+    }
+
+    fn get_description(&self) -> &'static str {
+        T::DESCRIPTION
+    }
+
+    fn get_children(&self) -> &[SubCommand] {
+        T::CHILDREN
+    }
+}
+
+impl<
+        T: ShellCommand<M, DEPTH, LINE>,
+        M: RawMutex + 'static,
+        const DEPTH: usize,
+        const LINE: usize,
+    > Command<M, DEPTH, LINE> for RawTypedCommand<T, M, DEPTH, LINE>
+{
+    fn get_channel(&self) -> &Channel<M, Request<LINE>, DEPTH> {
+        &self.channel
+    }
+}
+
+pub use shell_derive::ShellCommand;
+
+/// How replies are written, switched with the `mode` builtin.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputMode {
+    /// Lines as the handler wrote them, with errors as `error: ..`.
+    #[default]
+    Text,
+    /// One JSON object per request with `id`, `status`, `error` and `data`
+    /// fields, written once the request is done. `data` holds the lines
+    /// the request wrote.
+    Json,
+}
+
+impl OutputMode {
+    const KEYWORDS: [&'static str; 2] = ["text", "json"];
+
+    fn name(self) -> &'static str {
+        match self {
+            OutputMode::Text => "text",
+            OutputMode::Json => "json",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonReply<'a> {
+    id: Option<u32>,
+    status: &'a str,
+    error: Option<&'a str>,
+    data: &'a str,
+}
+
+/// A reply line for a transport that encodes replies itself, keeping the
+/// request id and whether the line reports an error.
+///
+/// Every request ends with a reply marked `done`, without a line, whose
+/// `error` tells whether the request failed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TaggedReply {
+    pub id: Option<u32>,
+    pub error: bool,
+    pub done: bool,
+    pub line: ReplyLine,
+}
+
+/// Replies on their way to one transport, with the state the shell keeps
+/// for that transport's requests.
+pub struct RawReplyChannel<M: RawMutex, T, const N: usize> {
+    queue: Channel<M, T, N>,
+    state: Mutex<M, RefCell<SinkState>>,
+}
+
+struct SinkState {
+    mode: OutputMode,
+    /// Requests queued for commands that have not been taken back up.
+    open: usize,
+    settled: WakerRegistration,
+    /// The first error and the lines of the request underway, kept for its
+    /// JSON object or final tagged reply.
+    error: Option<ReplyLine>,
+    data: ReplyLine,
+}
+
+impl<M: RawMutex, T, const N: usize> RawReplyChannel<M, T, N> {
+    pub const fn new() -> Self {
+        Self {
+            queue: Channel::new(),
+            state: Mutex::new(RefCell::new(SinkState {
+                mode: OutputMode::Text,
+                open: 0,
+                settled: WakerRegistration::new(),
+                error: None,
+                data: String::new(),
+            })),
+        }
+    }
+
+    /// The format replies are currently written in.
+    pub fn mode(&self) -> OutputMode {
+        self.state.lock(|state| state.borrow().mode)
+    }
+
+    pub fn set_mode(&self, mode: OutputMode) {
+        self.state.lock(|state| state.borrow_mut().mode = mode);
+    }
+
+    pub async fn send(&self, value: T) {
+        self.queue.send(value).await
+    }
+
+    pub async fn receive(&self) -> T {
+        self.queue.receive().await
+    }
+
+    pub fn try_receive(&self) -> Result<T, TryReceiveError> {
+        self.queue.try_receive()
+    }
+}
+
+impl<M: RawMutex, T, const N: usize> Default for RawReplyChannel<M, T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A reply channel of any mutex and size.
+trait ReplyQueue<T>: Sync {
+    fn try_send(&self, value: T) -> Result<(), TrySendError<T>>;
+    fn poll_ready_to_send(&self, cx: &mut Context<'_>) -> Poll<()>;
+    fn with_state(&self, f: &mut dyn FnMut(&mut SinkState));
+}
+
+impl<M: RawMutex + Sync, T: Send, const N: usize> ReplyQueue<T> for RawReplyChannel<M, T, N> {
+    fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.queue.try_send(value)
+    }
+
+    fn poll_ready_to_send(&self, cx: &mut Context<'_>) -> Poll<()> {
+        self.queue.poll_ready_to_send(cx)
+    }
+
+    fn with_state(&self, f: &mut dyn FnMut(&mut SinkState)) {
+        self.state.lock(|state| f(&mut state.borrow_mut()));
+    }
+}
+
+/// Sends `value`, waiting for room as [`Channel::send`] does.
+async fn send_reply<T>(queue: &dyn ReplyQueue<T>, mut value: T) {
+    loop {
+        match queue.try_send(value) {
+            Ok(()) => return,
+            Err(TrySendError::Full(full)) => value = full,
+        }
+        poll_fn(|cx| queue.poll_ready_to_send(cx)).await;
+    }
+}
+
+#[derive(Clone, Copy)]
+enum ReplySink {
+    Detached,
+    Lines(&'static dyn ReplyQueue<ReplyLine>),
+    Tagged(&'static dyn ReplyQueue<TaggedReply>),
+}
+
+impl ReplySink {
+    /// Runs `f` on the state of the channel, if there is one.
+    fn state<R>(self, f: impl FnOnce(&mut SinkState) -> R) -> Option<R> {
+        let mut f = Some(f);
+        let mut out = None;
+        let mut call = |state: &mut SinkState| out = f.take().map(|f| f(state));
+        match self {
+            ReplySink::Detached => {}
+            ReplySink::Lines(queue) => queue.with_state(&mut call),
+            ReplySink::Tagged(queue) => queue.with_state(&mut call),
         }
+        out
     }
 }
 
-impl<const N: usize> Command for RootCommand<N> {
-    fn get_root(&self) -> &'static str {
-        self.root
-    }
+/// Handle for sending output lines back to the transport a command arrived on.
+///
+/// A detached responder logs its lines instead.
+#[derive(Clone, Copy)]
+pub struct Responder {
+    sink: ReplySink,
+    mode: OutputMode,
+    id: Option<u32>,
+}
+
+impl Responder {
+    pub const fn new<M: RawMutex + Sync + 'static, const N: usize>(
+        channel: &'static ReplyChannel<M, N>,
+    ) -> Self {
+        Self::with_sink(ReplySink::Lines(channel))
+    }
+
+    /// A responder for a transport that encodes replies itself. Lines are
+    /// sent unformatted, and the output mode stays [`OutputMode::Text`].
+    pub const fn tagged<M: RawMutex + Sync + 'static, const N: usize>(
+        channel: &'static TaggedReplyChannel<M, N>,
+    ) -> Self {
+        Self::with_sink(ReplySink::Tagged(channel))
+    }
+
+    pub const fn detached() -> Self {
+        Self::with_sink(ReplySink::Detached)
+    }
+
+    const fn with_sink(sink: ReplySink) -> Self {
+        Self {
+            sink,
+            mode: OutputMode::Text,
+            id: None,
+        }
+    }
+
+    pub const fn with_mode(mut self, mode: OutputMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Tags JSON replies with the correlation id of the request.
+    pub const fn with_id(mut self, id: Option<u32>) -> Self {
+        self.id = id;
+        self
+    }
+
+    /// Sends a single line, truncated to [`MAX_REPLY_LEN`] bytes.
+    pub async fn reply(&self, line: &str) {
+        self.reply_fmt(format_args!("{}", line)).await;
+    }
+
+    /// Formats a single line, truncated to [`MAX_REPLY_LEN`] bytes.
+    pub async fn reply_fmt(&self, args: fmt::Arguments<'_>) {
+        self.write(false, args).await;
+    }
+
+    /// Reports a failure, as `error: ..` or a JSON error object.
+    pub async fn error(&self, error: impl fmt::Display) {
+        self.error_fmt(format_args!("{}", error)).await;
+    }
+
+    pub async fn error_fmt(&self, args: fmt::Arguments<'_>) {
+        self.write(true, args).await;
+    }
+
+    async fn write(&self, error: bool, args: fmt::Arguments<'_>) {
+        let mut out = TruncatingWriter(ReplyLine::new());
+        if let ReplySink::Tagged(channel) = self.sink {
+            let _ = out.write_fmt(args);
+            if error {
+                self.sink.state(|state| {
+                    state.error.get_or_insert_with(|| out.0.clone());
+                });
+            }
+            let reply = TaggedReply {
+                id: self.id,
+                error,
+                done: false,
+                line: out.0,
+            };
+            send_reply(channel, reply).await;
+            return;
+        }
+        match self.mode {
+            OutputMode::Text if error => {
+                let _ = write!(out, "error: {}", args);
+            }
+            OutputMode::Text => {
+                let _ = out.write_fmt(args);
+            }
+            OutputMode::Json => {
+                // Held for the object written when the request is done.
+                self.sink.state(|state| match &mut state.error {
+                    None if error => {
+                        let _ = out.write_fmt(args);
+                        state.error = Some(out.0);
+                    }
+                    _ if error => {}
+                    _ => {
+                        let mut data = TruncatingWriter(&mut state.data);
+                        if !data.0.is_empty() {
+                            let _ = data.write_char('\n');
+                        }
+                        let _ = data.write_fmt(args);
+                    }
+                });
+                return;
+            }
+        }
+        self.send(out.0).await;
+    }
+
+    /// The output mode of the channel, for the replies to a new request.
+    fn current_mode(self) -> Self {
+        match self.sink {
+            ReplySink::Lines(_) => self.with_mode(self.sink.state(|s| s.mode).unwrap_or_default()),
+            _ => self,
+        }
+    }
+
+    /// Sets the output mode of the channel. Only channels of text lines
+    /// have a choice.
+    fn set_mode(&self, mode: OutputMode) -> Result<(), ShellError> {
+        match self.sink {
+            ReplySink::Lines(_) => {
+                self.sink.state(|state| state.mode = mode);
+                Ok(())
+            }
+            _ if mode == OutputMode::Text => Ok(()),
+            _ => Err(ShellError::InvalidArg("mode")),
+        }
+    }
+
+    /// Whether a request's replies end with one written by
+    /// [`Responder::complete`].
+    fn has_final_reply(&self) -> bool {
+        match self.sink {
+            ReplySink::Lines(_) => self.mode == OutputMode::Json,
+            ReplySink::Tagged(_) => true,
+            ReplySink::Detached => false,
+        }
+    }
+
+    /// Starts a request, dropping what is left of the last one.
+    fn start(&self) {
+        if self.has_final_reply() {
+            self.sink.state(|state| {
+                state.error = None;
+                state.data.clear();
+            });
+        }
+    }
+
+    /// Finishes a request once the commands it queued are done with it,
+    /// writing its JSON object or final tagged reply.
+    async fn complete(&self) {
+        if !self.has_final_reply() {
+            return;
+        }
+        poll_fn(|cx| {
+            self.sink
+                .state(|state| match state.open {
+                    0 => Poll::Ready(()),
+                    _ => {
+                        state.settled.register(cx.waker());
+                        Poll::Pending
+                    }
+                })
+                .unwrap_or(Poll::Ready(()))
+        })
+        .await;
+        let taken = self
+            .sink
+            .state(|state| (state.error.take(), core::mem::take(&mut state.data)));
+        let Some((error, data)) = taken else {
+            return;
+        };
+        match self.sink {
+            ReplySink::Tagged(channel) => {
+                let reply = TaggedReply {
+                    id: self.id,
+                    error: error.is_some(),
+                    done: true,
+                    line: ReplyLine::new(),
+                };
+                send_reply(channel, reply).await;
+            }
+            _ => self.send(self.json(error, data)).await,
+        }
+    }
+
+    /// Counts a request queued for a command, until [`Responder::release`].
+    fn hold(&self) {
+        self.sink.state(|state| state.open += 1);
+    }
+
+    /// Marks a request taken by [`Responder::hold`] as done with.
+    fn release(&self) {
+        self.sink.state(|state| {
+            state.open = state.open.saturating_sub(1);
+            if state.open == 0 {
+                state.settled.wake();
+            }
+        });
+    }
+
+    /// Wraps a request's outcome in a JSON object, shortening the data and
+    /// then the error until the object fits in a line.
+    fn json(&self, mut error: Option<ReplyLine>, mut data: ReplyLine) -> ReplyLine {
+        loop {
+            let reply = JsonReply {
+                id: self.id,
+                status: if error.is_some() { "error" } else { "ok" },
+                error: error.as_deref(),
+                data: &data,
+            };
+            match serde_json_core::to_string(&reply) {
+                Ok(line) => return line,
+                Err(_) if data.pop().is_some() => {}
+                Err(_) if error.as_mut().and_then(String::pop).is_some() => {}
+                Err(_) => return ReplyLine::new(),
+            }
+        }
+    }
+
+    async fn send(&self, line: ReplyLine) {
+        match self.sink {
+            ReplySink::Lines(channel) => send_reply(channel, line).await,
+            ReplySink::Tagged(_) | ReplySink::Detached => info!("{}", line.as_str()),
+        }
+    }
+}
+
+struct TruncatingWriter<S>(S);
+
+impl<const N: usize> Write for TruncatingWriter<String<N>> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        TruncatingWriter(&mut self.0).write_str(s)
+    }
+}
+
+impl<const N: usize> Write for TruncatingWriter<&mut String<N>> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.0.push(c).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A dispatched command together with the way to answer it.
+pub struct Request<const LINE: usize = MAX_LINE_LEN> {
+    pub command: ParsedCommand<LINE>,
+    pub reply: Responder,
+}
+
+/// A node in a command tree. Any node can be dispatched to, taking its
+/// arguments, and may have children of its own. The words after a node
+/// that takes arguments are always its arguments, so only nodes without
+/// arguments lead on to their children.
+///
+/// A node is handled by the command whose tree it is in, or by a command of
+/// its own registered with the node's path as its root (see
+/// [`RawShell::register`]).
+pub struct SubCommand {
+    pub command: &'static str,
+    pub description: &'static str,
+    pub args: &'static [Arg],
+    pub children: &'static [SubCommand],
+}
+
+impl SubCommand {
+    /// Groups are listed through their children rather than on their own.
+    fn is_group(&self) -> bool {
+        !self.children.is_empty() && self.args.is_empty()
+    }
+
+    fn write_usage(&self, path: &[&str], out: &mut impl Write) -> fmt::Result {
+        for (i, name) in path.iter().enumerate() {
+            if i > 0 {
+                out.write_char(' ')?;
+            }
+            out.write_str(name)?;
+        }
+        for arg in self.args {
+            write!(out, " {}", arg)?;
+        }
+        Ok(())
+    }
+}
+
+/// Follows `tokens` down from `children` for as long as they name nodes,
+/// stopping at a node that takes arguments. Returns the nodes passed
+/// through and the tokens left over.
+fn walk<'a, 't, S: AsRef<str>>(
+    mut children: &'a [SubCommand],
+    mut tokens: &'t [S],
+) -> (Vec<&'a SubCommand, MAX_DEPTH>, &'t [S]) {
+    let mut nodes = Vec::new();
+    while let Some((token, rest)) = tokens.split_first() {
+        let Some(node) = children.iter().find(|c| c.command == token.as_ref()) else {
+            break;
+        };
+        if nodes.push(node).is_err() {
+            break;
+        }
+        children = node.children;
+        tokens = rest;
+        if !node.args.is_empty() {
+            break;
+        }
+    }
+    (nodes, tokens)
+}
+
+/// How many of `words` `root` takes, if its space separated words lead
+/// them.
+fn root_len(root: &str, words: &[&str]) -> Option<usize> {
+    let mut len = 0;
+    for part in root.split(' ') {
+        if words.get(len) != Some(&part) {
+            return None;
+        }
+        len += 1;
+    }
+    Some(len)
+}
+
+/// The word of `root` after `words`, if `root` starts with them and goes on.
+fn next_root_word(root: &'static str, words: &[&str]) -> Option<&'static str> {
+    let mut parts = root.split(' ');
+    for word in words {
+        if parts.next()? != *word {
+            return None;
+        }
+    }
+    parts.next()
+}
+
+/// Whether `mount`'s root names `base` itself or a node in `base`'s tree,
+/// so a line could be meant for either.
+fn claims(base: &(impl CommandSpec + ?Sized), mount: &(impl CommandSpec + ?Sized)) -> bool {
+    let words: Vec<&str, MAX_TOKENS> = mount.get_root().split(' ').take(MAX_TOKENS).collect();
+    match root_len(base.get_root(), &words) {
+        Some(len) => len == words.len() || !walk(base.get_children(), &words[len..]).0.is_empty(),
+        None => false,
+    }
+}
+
+/// What follows the `alias` or `macro` that starts `piece`: the rest of
+/// `line`, as a definition may hold `;`.
+fn definition<'a>(line: &'a str, piece: Piece<'_>) -> &'a str {
+    split_word(line[piece.start..].trim()).1
+}
+
+/// Splits a leading `#<id>` off `line`.
+fn split_id(line: &str) -> Result<(Option<u32>, &str), ShellError> {
+    let Some(tagged) = line.strip_prefix(ID_PREFIX) else {
+        return Ok((None, line));
+    };
+    let (id, rest) = tagged
+        .split_once(|c: char| c.is_ascii_whitespace())
+        .unwrap_or((tagged, ""));
+    let id = id.parse().map_err(|_| ShellError::InvalidArg("id"))?;
+    Ok((Some(id), rest.trim_start()))
+}
+
+/// Matches the words after the root against `command`'s tree and validates
+/// the arguments of the node they lead to.
+fn parse<const LINE: usize>(
+    command: &(impl CommandSpec + ?Sized),
+    tokens: &[&str],
+) -> Result<ParsedCommand<LINE>, ShellError> {
+    let (nodes, tokens) = walk(command.get_children(), tokens);
+    let (children, specs) = match nodes.last() {
+        Some(node) => (node.children, node.args),
+        None => (command.get_children(), &[][..]),
+    };
+
+    if !tokens.is_empty() && specs.is_empty() && !children.is_empty() {
+        return Err(ShellError::UnknownSubcommand);
+    }
+    if tokens.len() < specs.len() {
+        return Err(ShellError::TooFewArgs {
+            expected: specs.len(),
+            got: tokens.len(),
+        });
+    }
+    if tokens.len() > specs.len() {
+        return Err(ShellError::TooManyArgs);
+    }
+
+    let mut args = Tokens::default();
+    for (spec, token) in specs.iter().zip(tokens) {
+        spec.parse(token)?;
+        // The tokens came from a line of at most `LINE` bytes.
+        args.push_token(token)?;
+    }
+
+    Ok(ParsedCommand {
+        root: command.get_root(),
+        path: nodes.iter().map(|node| node.command).collect(),
+        specs,
+        args,
+    })
+}
+
+pub struct RawRootCommand<
+    M: RawMutex + 'static,
+    const N: usize,
+    const DEPTH: usize,
+    const LINE: usize = MAX_LINE_LEN,
+> {
+    pub root: &'static str,
+    pub description: &'static str,
+    pub children: [SubCommand; N],
+    pub channel: Channel<M, Request<LINE>, DEPTH>,
+}
+
+pub type RootCommand<const N: usize> = RawRootCommand<CriticalSectionRawMutex, N, QUEUE_DEPTH>;
+
+impl<M: RawMutex + 'static, const N: usize, const DEPTH: usize, const LINE: usize>
+    RawRootCommand<M, N, DEPTH, LINE>
+{
+    pub const fn new(
+        root: &'static str,
+        description: &'static str,
+        children: [SubCommand; N],
+    ) -> Self {
+        Self {
+            root,
+            description,
+            children,
+            channel: Channel::new(),
+        }
+    }
+}
+
+impl<M: RawMutex + 'static, const N: usize, const DEPTH: usize, const LINE: usize> CommandSpec
+    for RawRootCommand<M, N, DEPTH, LINE>
+{
+    fn get_root(&self) -> &'static str {
+        self.root
+    }
+
+    fn get_description(&self) -> &'static str {
+        self.description
+    }
+
+    fn get_children(&self) -> &[SubCommand] {
+        &self.children
+    }
+}
+
+impl<M: RawMutex + 'static, const N: usize, const DEPTH: usize, const LINE: usize>
+    Command<M, DEPTH, LINE> for RawRootCommand<M, N, DEPTH, LINE>
+{
+    fn get_channel(&self) -> &Channel<M, Request<LINE>, DEPTH> {
+        &self.channel
+    }
+}
+
+/// What the leading words of a line name.
+enum Target<'a, M: RawMutex + 'static, const DEPTH: usize, const LINE: usize> {
+    Queued(Registration<M, DEPTH, LINE>),
+    /// An inline handler, with its index.
+    Inline(usize, &'a dyn CommandSpec),
+}
+
+impl<M: RawMutex + 'static, const DEPTH: usize, const LINE: usize> Target<'_, M, DEPTH, LINE> {
+    fn spec(&self) -> &dyn CommandSpec {
+        match self {
+            Target::Queued(registration) => registration,
+            Target::Inline(_, handler) => *handler,
+        }
+    }
+}
+
+/// A registered command, tagged so a sender can tell it was unregistered
+/// even if it has been registered again since.
+///
+/// The command's parts are kept rather than the command, so the shell is
+/// `Send` and `Sync` whenever its channels are.
+struct Registration<M: RawMutex + 'static, const DEPTH: usize, const LINE: usize> {
+    root: &'static str,
+    description: &'static str,
+    children: &'static [SubCommand],
+    channel: &'static Channel<M, Request<LINE>, DEPTH>,
+    id: u32,
+    backpressure: Backpressure,
+}
+
+impl<M: RawMutex + 'static, const DEPTH: usize, const LINE: usize> Clone
+    for Registration<M, DEPTH, LINE>
+{
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<M: RawMutex + 'static, const DEPTH: usize, const LINE: usize> Copy
+    for Registration<M, DEPTH, LINE>
+{
+}
+
+impl<M: RawMutex + 'static, const DEPTH: usize, const LINE: usize> CommandSpec
+    for Registration<M, DEPTH, LINE>
+{
+    fn get_root(&self) -> &'static str {
+        self.root
+    }
+
+    fn get_description(&self) -> &'static str {
+        self.description
+    }
+
+    fn get_children(&self) -> &[SubCommand] {
+        self.children
+    }
+}
+
+type Registrations<M, const DEPTH: usize, const LINE: usize, const REGISTRY: usize> =
+    Vec<Registration<M, DEPTH, LINE>, REGISTRY>;
+
+struct Registry<M: RawMutex + 'static, const DEPTH: usize, const LINE: usize, const REGISTRY: usize>
+{
+    commands: Registrations<M, DEPTH, LINE, REGISTRY>,
+    next_id: u32,
+    /// Senders waiting on a full queue, woken when a command is unregistered.
+    blocked: MultiWakerRegistration<BLOCKED_SENDERS>,
+}
+
+/// Dispatches lines of up to `LINE` bytes to at most `REGISTRY` commands,
+/// each queueing `DEPTH` requests, with the registry guarded by `M`.
+///
+/// `H` holds the commands handled inline, fixed when the shell is built.
+pub struct RawShell<
+    M: RawMutex + 'static,
+    const LINE: usize,
+    const DEPTH: usize,
+    const REGISTRY: usize,
+    H: CommandHandlers<LINE> = (),
+> {
+    commands: Mutex<M, RefCell<Registry<M, DEPTH, LINE, REGISTRY>>>,
+    aliases: Mutex<M, RefCell<AliasStore<MAX_ALIASES, MAX_ALIAS_LEN>>>,
+    handlers: H,
+}
+
+pub type Shell = RawShell<CriticalSectionRawMutex, MAX_LINE_LEN, QUEUE_DEPTH, REGISTRY_LEN>;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ShellError {
+    #[error("unknown command")]
+    UnknownCommand,
+    #[error("unknown subcommand")]
+    UnknownSubcommand,
+    #[error("too few arguments: expected {expected}, got {got}")]
+    TooFewArgs { expected: usize, got: usize },
+    #[error("too many arguments")]
+    TooManyArgs,
+    #[error("invalid value for argument '{0}'")]
+    InvalidArg(&'static str),
+    #[error("line too long")]
+    LineTooLong,
+    #[error("too many tokens")]
+    TooManyTokens,
+    #[error("token too long")]
+    TokenTooLong,
+    #[error("unterminated quote")]
+    UnterminatedQuote,
+    #[error("dangling escape")]
+    DanglingEscape,
+    #[error("command registry is full")]
+    RegistryFull,
+    #[error("command already registered")]
+    DuplicateCommand,
+    #[error("command busy")]
+    Busy,
+    #[error("request dropped")]
+    Dropped,
+    #[error("alias store is full")]
+    AliasStoreFull,
+    #[error("alias expansion too long")]
+    AliasTooLong,
+    #[error("alias expansion too deep")]
+    AliasTooDeep,
+    #[error("unknown alias")]
+    UnknownAlias,
+    #[error("command unregistered")]
+    Unregistered,
+}
+
+impl From<TokenizeError> for ShellError {
+    fn from(error: TokenizeError) -> Self {
+        match error {
+            TokenizeError::TokenTooLong => ShellError::TokenTooLong,
+            TokenizeError::TooManyTokens => ShellError::TooManyTokens,
+            TokenizeError::UnterminatedQuote => ShellError::UnterminatedQuote,
+            TokenizeError::DanglingEscape => ShellError::DanglingEscape,
+        }
+    }
+}
+
+impl From<AliasError> for ShellError {
+    fn from(error: AliasError) -> Self {
+        match error {
+            AliasError::StoreFull => ShellError::AliasStoreFull,
+            AliasError::TooLong => ShellError::AliasTooLong,
+            AliasError::TooDeep => ShellError::AliasTooDeep,
+            AliasError::Unknown => ShellError::UnknownAlias,
+            AliasError::TooFewArgs { expected, got } => ShellError::TooFewArgs { expected, got },
+            AliasError::TooManyArgs => ShellError::TooManyArgs,
+        }
+    }
+}
+
+impl<
+        M: RawMutex + 'static,
+        const LINE: usize,
+        const DEPTH: usize,
+        const REGISTRY: usize,
+        H: CommandHandlers<LINE> + Default,
+    > Default for RawShell<M, LINE, DEPTH, REGISTRY, H>
+{
+    fn default() -> Self {
+        Self::with_handlers(H::default())
+    }
+}
+
+impl<M: RawMutex + 'static, const LINE: usize, const DEPTH: usize, const REGISTRY: usize>
+    RawShell<M, LINE, DEPTH, REGISTRY>
+{
+    pub const fn new() -> Self {
+        Self::with_handlers(())
+    }
+}
+
+impl<
+        M: RawMutex + 'static,
+        const LINE: usize,
+        const DEPTH: usize,
+        const REGISTRY: usize,
+        H: CommandHandlers<LINE>,
+    > RawShell<M, LINE, DEPTH, REGISTRY, H>
+{
+    /// A shell that runs `handlers` inline besides the commands registered
+    /// later.
+    pub const fn with_handlers(handlers: H) -> Self {
+        Self {
+            commands: Mutex::new(RefCell::new(Registry {
+                commands: Vec::new(),
+                next_id: 0,
+                blocked: MultiWakerRegistration::new(),
+            })),
+            aliases: Mutex::new(RefCell::new(AliasStore::new())),
+            handlers,
+        }
+    }
+
+    /// Adds `command` to the registry until the returned [`Receiver`] is
+    /// dropped or unregistered. Roots must be unique.
+    ///
+    /// A root of several words, such as `matrix brightness`, mounts the
+    /// command at that node: lines starting with those words go to it rather
+    /// than to `matrix`. The node must not already be in another command's
+    /// tree.
+    pub async fn register(
+        &self,
+        command: &'static dyn Command<M, DEPTH, LINE>,
+    ) -> Result<Receiver<'_, M, DEPTH, LINE>, ShellError> {
+        self.register_with(command, Backpressure::default()).await
+    }
+
+    /// Registers `command`, handling a full queue with `backpressure`.
+    pub async fn register_with(
+        &self,
+        command: &'static dyn Command<M, DEPTH, LINE>,
+        backpressure: Backpressure,
+    ) -> Result<Receiver<'_, M, DEPTH, LINE>, ShellError> {
+        let root = command.get_root();
+        let overlaps = |other: &dyn CommandSpec| claims(other, command) || claims(command, other);
+        if self.handlers().any(overlaps) {
+            return Err(ShellError::DuplicateCommand);
+        }
+        let id = self.commands.lock(|registry| {
+            let mut registry = registry.borrow_mut();
+            let builtin = root
+                .split(' ')
+                .next()
+                .is_some_and(|word| BUILTINS.contains(&word));
+            let taken = registry.commands.iter().any(|r| overlaps(r));
+            if builtin || taken {
+                return Err(ShellError::DuplicateCommand);
+            }
+            let id = registry.next_id;
+            registry
+                .commands
+                .push(Registration {
+                    root,
+                    description: command.get_description(),
+                    children: command.get_children(),
+                    channel: command.get_channel(),
+                    id,
+                    backpressure,
+                })
+                .map_err(|_| ShellError::RegistryFull)?;
+            registry.next_id = id.wrapping_add(1);
+            Ok(id)
+        })?;
+        // Drop anything left over from a previous registration.
+        command.get_channel().clear();
+        Ok(Receiver {
+            shell: self,
+            channel: command.get_channel(),
+            id,
+            last: None,
+        })
+    }
+
+    /// Registers `T` with its requests queued on `command`, returning a
+    /// receiver that yields decoded requests.
+    pub async fn register_typed<T: ShellCommand<M, DEPTH, LINE>>(
+        &self,
+        command: &'static RawTypedCommand<T, M, DEPTH, LINE>,
+    ) -> Result<TypedReceiver<'_, T, M, DEPTH, LINE>, ShellError> {
+        Ok(TypedReceiver {
+            receiver: self.register_with(command, T::BACKPRESSURE).await?,
+            command: PhantomData,
+        })
+    }
+
+    /// The command whose root is the longest run of leading `words`, with
+    /// how many words that root takes.
+    fn resolve(&self, words: &[&str]) -> Option<(Target<'_, M, DEPTH, LINE>, usize)> {
+        let mut best = self.commands.lock(|registry| {
+            registry
+                .borrow()
+                .commands
+                .iter()
+                .filter_map(|r| Some((Target::Queued(*r), root_len(r.root, words)?)))
+                .max_by_key(|(_, len)| *len)
+        });
+        for (index, handler) in self.handlers().enumerate() {
+            if let Some(len) = root_len(handler.get_root(), words) {
+                if best.as_ref().is_none_or(|(_, best)| len > *best) {
+                    best = Some((Target::Inline(index, handler), len));
+                }
+            }
+        }
+        best
+    }
+
+    fn snapshot(&self) -> Registrations<M, DEPTH, LINE, REGISTRY> {
+        self.commands
+            .lock(|registry| registry.borrow().commands.clone())
+    }
+
+    /// Resolves once the registration tagged `id` is gone.
+    async fn unregistered(&self, id: u32) {
+        poll_fn(|cx| {
+            self.commands.lock(|registry| {
+                let mut registry = registry.borrow_mut();
+                if registry.commands.iter().any(|r| r.id == id) {
+                    registry.blocked.register(cx.waker());
+                    Poll::Pending
+                } else {
+                    Poll::Ready(())
+                }
+            })
+        })
+        .await
+    }
+
+    fn handlers(&self) -> impl Iterator<Item = &dyn CommandSpec> {
+        (0..).map_while(|index| self.handlers.get(index))
+    }
+
+    pub async fn send(&self, raw_command: &str) -> Result<(), ShellError> {
+        self.send_with_reply(raw_command, Responder::detached())
+            .await
+    }
+
+    /// Dispatches a line, attaching `reply` so the handler can answer the
+    /// originating transport.
+    ///
+    /// The line may hold several commands separated by `;`, and aliases and
+    /// macros are expanded first. Commands run in order until one fails. An
+    /// `alias` or `macro` definition takes the rest of the line, `;` and all.
+    ///
+    /// A leading `#<id>` is stripped and tags the replies. Replies follow the
+    /// [`OutputMode`] of `reply`'s channel, and a failure is reported through
+    /// `reply` as well as returned. In JSON mode this waits for the commands
+    /// the line queued to be done with it, to write its one object.
+    pub async fn send_with_reply(
+        &self,
+        raw_command: &str,
+        reply: Responder,
+    ) -> Result<(), ShellError> {
+        let mut reply = reply.current_mode();
+        let result = match split_id(raw_command.trim()) {
+            _ if raw_command.len() > LINE => Err(ShellError::LineTooLong),
+            Ok((id, line)) => {
+                reply = reply.with_id(id);
+                reply.start();
+                self.run(line, reply).await
+            }
+            Err(e) => {
+                reply.start();
+                Err(e)
+            }
+        };
+        if let Err(e) = &result {
+            reply.error(e).await;
+        }
+        reply.complete().await;
+        result
+    }
+
+    /// Runs the `;`-separated commands of `line` in turn, expanding each
+    /// just before it runs so it sees the aliases the ones before it left.
+    async fn run(&self, line: &str, reply: Responder) -> Result<(), ShellError> {
+        for piece in split_unquoted(line, |c| c == ';') {
+            let (word, _) = split_word(piece.text.trim());
+            if word == ALIAS_ROOT || word == MACRO_ROOT {
+                return self.run_expanded(&line[piece.start..], reply).await;
+            }
+            let mut expanded: String<LINE> = String::new();
+            self.aliases
+                .lock(|aliases| aliases.borrow().expand(piece.text, &mut expanded))?;
+            self.run_expanded(&expanded, reply).await?;
+        }
+        Ok(())
+    }
+
+    /// Runs the commands an expansion came to, where `alias` and `macro`
+    /// take the rest of it as their definition.
+    async fn run_expanded(&self, expanded: &str, reply: Responder) -> Result<(), ShellError> {
+        for piece in split_unquoted(expanded, |c| c == ';') {
+            let command = piece.text.trim();
+            let (word, args) = split_word(command);
+            match word {
+                ALIAS_ROOT => {
+                    return self
+                        .define(Kind::Alias, definition(expanded, piece), reply)
+                        .await
+                }
+                MACRO_ROOT => {
+                    return self
+                        .define(Kind::Macro, definition(expanded, piece), reply)
+                        .await
+                }
+                UNALIAS_ROOT => self.unalias(args)?,
+                _ => self.dispatch(command, reply).await?,
+            }
+        }
+        Ok(())
+    }
+
+    /// The `alias` and `macro` builtins: list every alias or macro, show
+    /// one, or define one as the rest of the line.
+    async fn define(&self, kind: Kind, args: &str, reply: Responder) -> Result<(), ShellError> {
+        let (name, expansion) = split_word(args);
+
+        if !expansion.is_empty() {
+            if self.is_root(name) {
+                return Err(ShellError::DuplicateCommand);
+            }
+            return self
+                .aliases
+                .lock(|aliases| aliases.borrow_mut().define(kind, name, expansion.trim()))
+                .map_err(ShellError::from);
+        }
+
+        let builtin = match kind {
+            Kind::Alias => ALIAS_ROOT,
+            Kind::Macro => MACRO_ROOT,
+        };
+        for index in 0.. {
+            let line = self.aliases.lock(|aliases| {
+                let aliases = aliases.borrow();
+                let mut matching = aliases
+                    .iter()
+                    .filter(|(k, n, _)| *k == kind && (name.is_empty() || *n == name));
+                matching.nth(index).map(|(_, name, expansion)| {
+                    let mut line = TruncatingWriter(ReplyLine::new());
+                    let _ = write!(line, "{} {} {}", builtin, name, expansion);
+                    line.0
+                })
+            });
+            match line {
+                Some(line) => reply.reply(&line).await,
+                None if index == 0 && !name.is_empty() => return Err(ShellError::UnknownAlias),
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `name` is a builtin or the first word of a command's root, so
+    /// an alias of that name would hide it.
+    fn is_root(&self, name: &str) -> bool {
+        let first = |root: &'static str| root.split(' ').next() == Some(name);
+        BUILTINS.contains(&name)
+            || self.snapshot().iter().any(|r| first(r.root))
+            || self.handlers().any(|h| first(h.get_root()))
+    }
+
+    /// The `unalias` builtin, which removes an alias or a macro.
+    fn unalias(&self, args: &str) -> Result<(), ShellError> {
+        if args.is_empty() {
+            return Err(ShellError::TooFewArgs {
+                expected: 1,
+                got: 0,
+            });
+        }
+        let args = args.trim();
+        if args.contains(|c: char| c.is_ascii_whitespace()) {
+            return Err(ShellError::TooManyArgs);
+        }
+        self.aliases
+            .lock(|aliases| aliases.borrow_mut().remove(args))
+            .map_err(ShellError::from)
+    }
+
+    /// Validates and queues a single command.
+    async fn dispatch(&self, raw_command: &str, reply: Responder) -> Result<(), ShellError> {
+        let tokens: Tokens<LINE, MAX_TOKENS> = tokenize(raw_command)?;
+        let split_command: Vec<&str, MAX_TOKENS> = tokens.iter().collect();
+
+        let Some((&root_command, rest)) = split_command.split_first() else {
+            return Ok(());
+        };
+
+        if root_command == HELP_ROOT {
+            let mut text = TruncatingWriter(String::<HELP_BUF_LEN>::new());
+            self.help(rest, &mut text).await?;
+            for line in text.0.lines() {
+                reply.reply(line).await;
+            }
+            return Ok(());
+        }
+        if root_command == MODE_ROOT {
+            match rest {
+                [] => reply.reply(reply.mode.name()).await,
+                [mode] => match Arg::keyword("mode", &OutputMode::KEYWORDS).parse(mode)? {
+                    ArgValue::Keyword("json") => reply.set_mode(OutputMode::Json)?,
+                    _ => reply.set_mode(OutputMode::Text)?,
+                },
+                _ => return Err(ShellError::TooManyArgs),
+            }
+            return Ok(());
+        }
+
+        let (target, used) = self
+            .resolve(&split_command)
+            .ok_or(ShellError::UnknownCommand)?;
+        let rest = &split_command[used..];
+        let command = match target {
+            Target::Queued(command) => command,
+            Target::Inline(index, handler) => {
+                let parsed = parse(handler, rest)?;
+                return self.handlers.handle(index, parsed, reply).await;
+            }
+        };
+
+        let request = Request {
+            command: parse(&command, rest)?,
+            reply,
+        };
+        let channel = command.channel;
+        // Released by the receiver once the handler is done with the
+        // request, or here if it never gets there.
+        reply.hold();
+        match command.backpressure {
+            // Checked first, so a request is never queued for a receiver
+            // that has gone.
+            Backpressure::Block => {
+                match select(self.unregistered(command.id), channel.send(request)).await {
+                    Either::First(()) => {
+                        reply.release();
+                        return Err(ShellError::Unregistered);
+                    }
+                    Either::Second(()) => {}
+                }
+            }
+            Backpressure::DropNewest => {
+                if let Err(TrySendError::Full(request)) = channel.try_send(request) {
+                    warn!("{} busy, dropped request", command.root);
+                    request.reply.error(ShellError::Dropped).await;
+                    request.reply.release();
+                }
+            }
+            Backpressure::DropOldest => {
+                if let Err(TrySendError::Full(request)) = channel.try_send(request) {
+                    let oldest = channel.try_receive();
+                    warn!("{} busy, dropped oldest request", command.root);
+                    if let Err(TrySendError::Full(request)) = channel.try_send(request) {
+                        warn!("{} busy, dropped request", command.root);
+                        request.reply.error(ShellError::Dropped).await;
+                        request.reply.release();
+                    }
+                    if let Ok(oldest) = oldest {
+                        oldest.reply.error(ShellError::Dropped).await;
+                        oldest.reply.release();
+                    }
+                }
+            }
+            Backpressure::Reject => channel.try_send(request).map_err(|_| {
+                reply.release();
+                ShellError::Busy
+            })?,
+        }
+
+        Ok(())
+    }
+
+    /// Collects the roots, or the children of the node named by the earlier
+    /// words and the next words of roots mounted there, that the last word
+    /// of `line` is a prefix of. Returns where that word starts.
+    pub async fn complete<const N: usize>(
+        &self,
+        line: &str,
+        candidates: &mut Vec<&'static str, N>,
+    ) -> usize {
+        let word_start = line
+            .rfind(|c: char| c.is_ascii_whitespace())
+            .map_or(0, |i| i + 1);
+        let word = &line[word_start..];
+        let previous: Vec<&str, MAX_TOKENS> = line[..word_start]
+            .split_ascii_whitespace()
+            .take(MAX_TOKENS)
+            .collect();
+        let (words, builtins) = match previous.split_first() {
+            Some((&HELP_ROOT, topic)) => (topic, false),
+            _ => (&previous[..], previous.is_empty()),
+        };
+
+        let mut offer = |name: &'static str| {
+            if name.starts_with(word) && !candidates.contains(&name) {
+                let _ = candidates.push(name);
+            }
+        };
+
+        let roots = self.snapshot();
+        let roots = roots.iter().map(|r| r.root);
+        for root in roots.chain(self.handlers().map(|h| h.get_root())) {
+            if let Some(next) = next_root_word(root, words) {
+                offer(next);
+            }
+        }
+        if builtins {
+            BUILTINS.iter().for_each(|builtin| offer(builtin));
+        }
+
+        if let Some((target, used)) = self.resolve(words) {
+            let children = target.spec().get_children();
+            let (nodes, rest) = walk(children, &words[used..]);
+            let children = match nodes.last() {
+                None => children,
+                Some(node) if node.args.is_empty() => node.children,
+                Some(_) => &[],
+            };
+            if rest.is_empty() {
+                children.iter().for_each(|c| offer(c.command));
+            }
+        }
+
+        word_start
+    }
+
+    /// Writes the built-in `help` output: every registered root with its
+    /// usage lines, or the detailed usage of the subtree named by `topic`
+    /// followed by the commands mounted below it.
+    pub async fn help(&self, topic: &[&str], out: &mut impl Write) -> Result<(), ShellError> {
+        if topic.is_empty() {
+            for registration in self.snapshot() {
+                let _ = write_summary(&registration, out);
+            }
+            for handler in self.handlers() {
+                let _ = write_summary(handler, out);
+            }
+            let _ = writeln!(out, "{} [command] - List commands or show usage", HELP_ROOT);
+            let _ = writeln!(
+                out,
+                "{} [name [expansion]] - List or define aliases",
+                ALIAS_ROOT
+            );
+            let _ = writeln!(
+                out,
+                "{} [name [body]] - List or define macros taking $1..$9",
+                MACRO_ROOT
+            );
+            let _ = writeln!(out, "{} <name> - Remove an alias or macro", UNALIAS_ROOT);
+            let _ = writeln!(
+                out,
+                "{} [text|json] - Show or set the reply format",
+                MODE_ROOT
+            );
+            return Ok(());
+        }
+
+        let found = match self.resolve(topic) {
+            Some((target, used)) => write_topic(target.spec(), &topic[used..], out),
+            None => Err(ShellError::UnknownCommand),
+        };
+        let mut mounted = false;
+        for registration in self.snapshot() {
+            if next_root_word(registration.root, topic).is_some() {
+                let _ = write_summary(&registration, out);
+                mounted = true;
+            }
+        }
+        for handler in self.handlers() {
+            if next_root_word(handler.get_root(), topic).is_some() {
+                let _ = write_summary(handler, out);
+                mounted = true;
+            }
+        }
+        if mounted {
+            return Ok(());
+        }
+        found
+    }
+}
+
+impl<
+        M: RawMutex + 'static,
+        const LINE: usize,
+        const DEPTH: usize,
+        const REGISTRY: usize,
+        H: CommandHandlers<LINE>,
+    > Unregister for RawShell<M, LINE, DEPTH, REGISTRY, H>
+{
+    fn unregister(&self, id: u32) {
+        self.commands.lock(|registry| {
+            let mut registry = registry.borrow_mut();
+            if let Some(index) = registry.commands.iter().position(|r| r.id == id) {
+                let channel = registry.commands.remove(index).channel;
+                while let Ok(request) = channel.try_receive() {
+                    request.reply.release();
+                }
+            }
+            // Blocked senders give up rather than wait for a receiver that
+            // is gone.
+            registry.blocked.wake();
+        });
+    }
+}
+
+impl<
+        M: RawMutex + 'static,
+        const LINE: usize,
+        const DEPTH: usize,
+        const REGISTRY: usize,
+        H: CommandHandlers<LINE>,
+    > Completer for RawShell<M, LINE, DEPTH, REGISTRY, H>
+{
+    async fn complete(
+        &self,
+        line: &str,
+        candidates: &mut Vec<&'static str, MAX_COMPLETIONS>,
+    ) -> usize {
+        RawShell::complete(self, line, candidates).await
+    }
+}
+
+fn write_summary(command: &(impl CommandSpec + ?Sized), out: &mut impl Write) -> fmt::Result {
+    writeln!(
+        out,
+        "{} - {}",
+        command.get_root(),
+        command.get_description()
+    )?;
+    let mut path = Vec::new();
+    let _ = path.push(command.get_root());
+    write_nodes(&mut path, command.get_children(), false, out)
+}
+
+/// Writes the usage of the node `path` names below `command`.
+fn write_topic(
+    command: &(impl CommandSpec + ?Sized),
+    path: &[&str],
+    out: &mut impl Write,
+) -> Result<(), ShellError> {
+    let (nodes, rest) = walk(command.get_children(), path);
+    if !rest.is_empty() {
+        return Err(ShellError::UnknownSubcommand);
+    }
+    let _ = write_usage(command, &nodes, out);
+    Ok(())
+}
+
+/// Writes the usage of the node at the end of `nodes`, or of the whole
+/// command if empty, with descriptions. Groups get a heading line.
+fn write_usage(
+    command: &(impl CommandSpec + ?Sized),
+    nodes: &[&SubCommand],
+    out: &mut impl Write,
+) -> fmt::Result {
+    let mut path: Vec<&str, MAX_TOKENS> = Vec::new();
+    let _ = path.push(command.get_root());
+    let Some((node, parents)) = nodes.split_last() else {
+        writeln!(
+            out,
+            "{} - {}",
+            command.get_root(),
+            command.get_description()
+        )?;
+        return write_nodes(&mut path, command.get_children(), true, out);
+    };
+
+    parents.iter().for_each(|parent| {
+        let _ = path.push(parent.command);
+    });
+    if node.is_group() {
+        let _ = path.push(node.command);
+        node.write_usage(&path, out)?;
+        writeln!(out, " - {}", node.description)?;
+        path.pop();
+    }
+    write_nodes(&mut path, core::slice::from_ref(*node), true, out)
+}
+
+/// Writes a usage line for every node under `path` that isn't a group.
+fn write_nodes(
+    path: &mut Vec<&str, MAX_TOKENS>,
+    nodes: &[SubCommand],
+    detail: bool,
+    out: &mut impl Write,
+) -> fmt::Result {
+    for node in nodes {
+        if path.push(node.command).is_err() {
+            continue;
+        }
+        if !node.is_group() {
+            out.write_str("  ")?;
+            node.write_usage(path, out)?;
+            writeln!(out)?;
+            if detail {
+                writeln!(out, "      {}", node.description)?;
+            }
+        }
+        // Anything after a node with arguments is taken as those.
+        if node.args.is_empty() {
+            write_nodes(path, node.children, detail, out)?;
+        }
+        path.pop();
+    }
+    Ok(())
+}
+
+/// Registration handle for a [`Command`], used to receive its requests.
+///
+/// The command is unregistered, and its queued requests dropped, when the
+/// handle is dropped. Sends blocked on its full queue then fail with
+/// [`ShellError::Unregistered`].
+pub struct Receiver<
+    'a,
+    M: RawMutex + 'static = CriticalSectionRawMutex,
+    const DEPTH: usize = QUEUE_DEPTH,
+    const LINE: usize = MAX_LINE_LEN,
+> {
+    shell: &'a dyn Unregister,
+    channel: &'static Channel<M, Request<LINE>, DEPTH>,
+    id: u32,
+    /// Where the last request came from, released when the next is asked
+    /// for.
+    last: Option<Responder>,
+}
+
+/// The part of a shell a [`Receiver`] needs, independent of its capacities.
+trait Unregister {
+    fn unregister(&self, id: u32);
+}
+
+impl<M: RawMutex + 'static, const DEPTH: usize, const LINE: usize> Receiver<'_, M, DEPTH, LINE> {
+    /// Waits for the next request, which also marks the last one as done.
+    pub async fn get(&mut self) -> Request<LINE> {
+        if let Some(reply) = self.last.take() {
+            reply.release();
+        }
+        let request = self.channel.receive().await;
+        self.last = Some(request.reply);
+        request
+    }
+
+    /// Unregisters the command now rather than when the handle is dropped.
+    pub fn unregister(self) {}
+}
+
+impl<M: RawMutex + 'static, const DEPTH: usize, const LINE: usize> Drop
+    for Receiver<'_, M, DEPTH, LINE>
+{
+    fn drop(&mut self) {
+        if let Some(reply) = self.last.take() {
+            reply.release();
+        }
+        self.shell.unregister(self.id);
+    }
+}
+
+pub struct TypedRequest<T> {
+    pub command: T,
+    pub reply: Responder,
+}
+
+/// A [`Receiver`] that decodes each request into `T`.
+pub struct TypedReceiver<
+    'a,
+    T,
+    M: RawMutex + 'static = CriticalSectionRawMutex,
+    const DEPTH: usize = QUEUE_DEPTH,
+    const LINE: usize = MAX_LINE_LEN,
+> {
+    receiver: Receiver<'a, M, DEPTH, LINE>,
+    command: PhantomData<fn() -> T>,
+}
+
+impl<
+        T: ShellCommand<M, DEPTH, LINE>,
+        M: RawMutex + 'static,
+        const DEPTH: usize,
+        const LINE: usize,
+    > TypedReceiver<'_, T, M, DEPTH, LINE>
+{
+    /// Waits for the next request that decodes. Requests that don't, such
+    /// as a bare root, are answered with the error and skipped.
+    pub async fn get(&mut self) -> TypedRequest<T> {
+        loop {
+            let request = self.receiver.get().await;
+            match T::from_parsed(&request.command) {
+                Ok(command) => {
+                    return TypedRequest {
+                        command,
+                        reply: request.reply,
+                    }
+                }
+                Err(e) => request.reply.error(e).await,
+            }
+        }
+    }
+
+    /// Unregisters the command now rather than when the handle is dropped.
+    pub fn unregister(self) {}
+}
+
+#[cfg(test)]
+mod test {
+    use embassy_time::{Duration, WithTimeout};
+
+    use super::*;
+
+    type TestShell<M> = RawShell<M, MAX_LINE_LEN, QUEUE_DEPTH, REGISTRY_LEN>;
+    type TestCommand<M, const N: usize> = RawRootCommand<M, N, QUEUE_DEPTH>;
+
+    /// Shell tests are generic over the raw mutex and run once per type by
+    /// [`mutex_tests`], so commands are leaked rather than `static`.
+    fn leak<T>(value: T) -> &'static T {
+        Box::leak(Box::new(value))
+    }
+
+    macro_rules! mutex_tests {
+        ($($test:ident),* $(,)?) => {
+            mod critical_section {
+                use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex as M;
+                $(
+                    #[futures_test::test]
+                    async fn $test() {
+                        super::$test::<M>().await
+                    }
+                )*
+            }
+
+            mod noop {
+                use embassy_sync::blocking_mutex::raw::NoopRawMutex as M;
+                $(
+                    #[futures_test::test]
+                    async fn $test() {
+                        super::$test::<M>().await
+                    }
+                )*
+            }
+        };
+    }
+
+    mutex_tests!(
+        basic_send_and_get,
+        basic_send_and_get_queue,
+        basic_send_and_get_two_receivers,
+        test_send_and_get_sub_cmd,
+        basic_send_and_get_with_arg,
+        basic_send_and_get_sub_cmd_with_too_many_args,
+        send_typed_args,
+        send_invalid_typed_args,
+        help_lists_commands,
+        handler_replies_to_sender,
+        send_reaches_any_sub_command,
+        send_dispatch_errors,
+        register_full_registry,
+        register_duplicate_root,
+        unregister_on_drop_and_reregister,
+        send_quoted_argument,
+        complete_roots_and_sub_commands,
+        backpressure_reject_and_drop,
+        stuck_handler_does_not_starve_others,
+        blocked_send_does_not_hold_registry,
+        unregister_fails_blocked_send,
+        nested_command_tree,
+        mounted_command_nodes,
+        aliases_expand_before_dispatch,
+        aliases_expand_as_commands_run,
+        inline_handlers,
+        json_mode_replies,
+        json_mode_waits_for_handlers,
+        tagged_replies_keep_id,
+    );
+
+    /// What a dispatched command is expected to have parsed into.
+    #[derive(Debug)]
+    struct Parsed<'a> {
+        root: &'static str,
+        path: &'a [&'static str],
+        args: &'a [ArgValue<'a>],
+    }
+
+    impl<const LINE: usize> PartialEq<Parsed<'_>> for ParsedCommand<LINE> {
+        fn eq(&self, other: &Parsed<'_>) -> bool {
+            self.root == other.root
+                && self.path.as_slice() == other.path
+                && self.args().eq(other.args.iter().copied())
+        }
+    }
+
+    fn parsed<'a>(
+        root: &'static str,
+        path: &'a [&'static str],
+        args: &'a [ArgValue<'a>],
+    ) -> Parsed<'a> {
+        Parsed { root, path, args }
+    }
+
+    async fn basic_send_and_get<M: RawMutex + 'static>() {
+        let shell = TestShell::<M>::new();
+
+        let root: &TestCommand<M, 0> = leak(RawRootCommand::new("Hello", "Say hello", []));
+        let mut receiver = shell.register(root).await.unwrap();
+
+        let command: String<256> = String::try_from("Hello").unwrap();
+        shell.send(&command).await.unwrap();
+        let out = receiver
+            .get()
+            .with_timeout(Duration::from_secs(1))
+            .await
+            .unwrap();
+
+        assert_eq!(out.command, parsed("Hello", &[], &[]));
+    }
+
+    async fn basic_send_and_get_queue<M: RawMutex + 'static>() {
+        let shell = TestShell::<M>::new();
+
+        let root: &TestCommand<M, 0> = leak(RawRootCommand {
+            root: "Hello",
+            description: "Say hello",
+            children: [],
+            channel: Channel::new(),
+        });
+
+        let mut receiver = shell.register(root).await.unwrap();
+
+        let command: String<256> = String::try_from("Hello").unwrap();
+        shell.send(&command).await.unwrap();
+        shell.send(&command).await.unwrap();
+
+        let out = receiver
+            .get()
+            .with_timeout(Duration::from_secs(1))
+            .await
+            .unwrap();
+
+        assert_eq!(out.command, parsed("Hello", &[], &[]));
+
+        let out = receiver
+            .get()
+            .with_timeout(Duration::from_secs(1))
+            .await
+            .unwrap();
+
+        assert_eq!(out.command, parsed("Hello", &[], &[]));
+    }
+
+    async fn basic_send_and_get_two_receivers<M: RawMutex + 'static>() {
+        let shell = TestShell::<M>::new();
+
+        let root_a: &TestCommand<M, 0> = leak(RawRootCommand {
+            root: "Hello",
+            description: "Say hello",
+            children: [],
+            channel: Channel::new(),
+        });
+
+        let root_b: &TestCommand<M, 0> = leak(RawRootCommand {
+            root: "Goodbye",
+            description: "Say goodbye",
+            children: [],
+            channel: Channel::new(),
+        });
+
+        let mut receiver_a = shell.register(root_a).await.unwrap();
+        let mut receiver_b = shell.register(root_b).await.unwrap();
+
+        let command_a: String<256> = String::try_from("Hello").unwrap();
+        let command_b: String<256> = String::try_from("Goodbye").unwrap();
+
+        shell.send(&command_a).await.unwrap();
+        shell.send(&command_b).await.unwrap();
+
+        let out_a = receiver_a
+            .get()
+            .with_timeout(Duration::from_secs(1))
+            .await
+            .unwrap();
+
+        assert_eq!(out_a.command, parsed("Hello", &[], &[]));
+
+        let out_b = receiver_b
+            .get()
+            .with_timeout(Duration::from_secs(1))
+            .await
+            .unwrap();
+
+        assert_eq!(out_b.command, parsed("Goodbye", &[], &[]));
+    }
+
+    async fn test_send_and_get_sub_cmd<M: RawMutex + 'static>() {
+        let shell = TestShell::<M>::new();
+
+        let root: &TestCommand<M, 1> = leak(
+            const {
+                RawRootCommand {
+                    root: "Hello",
+                    description: "Say hello",
+                    children: [SubCommand {
+                        command: "world",
+                        description: "Greet the world",
+                        args: &[],
+                        children: &[],
+                    }],
+                    channel: Channel::new(),
+                }
+            },
+        );
+        let mut rev = shell.register(root).await.unwrap();
+
+        let command: String<256> = String::try_from("Hello world").unwrap();
+        shell.send(&command).await.unwrap();
+
+        let out = rev
+            .get()
+            .with_timeout(Duration::from_secs(1))
+            .await
+            .unwrap();
+
+        assert_eq!(out.command, parsed("Hello", &["world"], &[]));
+    }
+
+    async fn basic_send_and_get_with_arg<M: RawMutex + 'static>() {
+        let shell = TestShell::<M>::new();
+
+        let root: &TestCommand<M, 1> = leak(
+            const {
+                RawRootCommand {
+                    root: "Hello",
+                    description: "Say hello",
+                    children: [SubCommand {
+                        command: "world",
+                        description: "Greet the world",
+                        args: &[Arg::int("count", 0, 10)],
+                        children: &[],
+                    }],
+                    channel: Channel::new(),
+                }
+            },
+        );
+
+        let mut rev = shell.register(root).await.unwrap();
+
+        let command: String<256> = String::try_from("Hello world 5").unwrap();
+        shell.send(&command).await.unwrap();
+        let out = rev
+            .get()
+            .with_timeout(Duration::from_secs(1))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            out.command,
+            parsed("Hello", &["world"], &[ArgValue::Int(5)])
+        );
+    }
+
+    async fn basic_send_and_get_sub_cmd_with_too_many_args<M: RawMutex + 'static>() {
+        let shell = TestShell::<M>::new();
+
+        let root: &TestCommand<M, 1> = leak(
+            const {
+                RawRootCommand {
+                    root: "Hello",
+                    description: "Say hello",
+                    children: [SubCommand {
+                        command: "world",
+                        description: "Greet the world",
+                        args: &[],
+                        children: &[],
+                    }],
+                    channel: Channel::new(),
+                }
+            },
+        );
+
+        let _rev = shell.register(root).await.unwrap();
+
+        let command: String<256> = String::try_from("Hello world 5").unwrap();
+        let out = shell.send(&command).await.unwrap_err();
+
+        assert_eq!(out, ShellError::TooManyArgs);
+    }
+
+    async fn send_typed_args<M: RawMutex + 'static>() {
+        let shell = TestShell::<M>::new();
+
+        let root: &TestCommand<M, 1> = leak(
+            const {
+                RawRootCommand {
+                    root: "Hello",
+                    description: "Say hello",
+                    children: [SubCommand {
+                        command: "world",
+                        description: "Greet the world",
+                        args: &[
+                            Arg::int("count", -5, 5),
+                            Arg::bool("enabled"),
+                            Arg::keyword("direction", &["left", "right"]),
+                            Arg::str("text"),
+                            Arg::duration("delay"),
+                        ],
+                        children: &[],
+                    }],
+                    channel: Channel::new(),
+                }
+            },
+        );
+
+        let mut rev = shell.register(root).await.unwrap();
+
+        let command: String<256> = String::try_from("Hello world -3 on right HI 2s").unwrap();
+        shell.send(&command).await.unwrap();
+        let out = rev
+            .get()
+            .with_timeout(Duration::from_secs(1))
+            .await
+            .unwrap();
+
+        let args = [
+            ArgValue::Int(-3),
+            ArgValue::Bool(true),
+            ArgValue::Keyword("right"),
+            ArgValue::Str("HI"),
+            ArgValue::Duration(Duration::from_secs(2)),
+        ];
+        assert_eq!(out.command, parsed("Hello", &["world"], &args));
+        assert_eq!(out.command.arg(3).and_then(|arg| arg.as_str()), Some("HI"));
+    }
+
+    async fn send_invalid_typed_args<M: RawMutex + 'static>() {
+        let shell = TestShell::<M>::new();
+
+        let root: &TestCommand<M, 1> = leak(
+            const {
+                RawRootCommand {
+                    root: "Hello",
+                    description: "Say hello",
+                    children: [SubCommand {
+                        command: "world",
+                        description: "Greet the world",
+                        args: &[
+                            Arg::int("count", 0, 10),
+                            Arg::keyword("direction", &["left", "right"]),
+                            Arg::duration("delay"),
+                        ],
+                        children: &[],
+                    }],
+                    channel: Channel::new(),
+                }
+            },
+        );
+
+        let _rev = shell.register(root).await.unwrap();
+
+        for (line, arg) in [
+            ("Hello world 11 left 5", "count"),
+            ("Hello world five left 5", "count"),
+            ("Hello world 1 up 5", "direction"),
+            ("Hello world 1 left 5m", "delay"),
+        ] {
+            let command: String<256> = String::try_from(line).unwrap();
+            let out = shell.send(&command).await.unwrap_err();
+            assert_eq!(out, ShellError::InvalidArg(arg));
+        }
+    }
+
+    #[test]
+    fn parse_duration_units() {
+        let arg = Arg::duration("delay");
+        for (token, millis) in [("250", 250), ("250ms", 250), ("3s", 3000)] {
+            let value = arg.parse(token).unwrap();
+            assert_eq!(value, ArgValue::Duration(Duration::from_millis(millis)));
+        }
+    }
+
+    async fn help_lists_commands<M: RawMutex + 'static>() {
+        let shell = TestShell::<M>::new();
+
+        let root: &TestCommand<M, 2> = leak(
+            const {
+                RawRootCommand {
+                    root: "scroll",
+                    description: "Scroll text across the matrix",
+                    children: [
+                        SubCommand {
+                            command: "forward",
+                            description: "Scroll text to the left",
+                            args: &[Arg::str("text")],
+                            children: &[],
+                        },
+                        SubCommand {
+                            command: "speed",
+                            description: "Set the frame time",
+                            args: &[Arg::int("ms", 10, 1000), Arg::keyword("unit", &["ms", "s"])],
+                            children: &[],
+                        },
+                    ],
+                    channel: Channel::new(),
+                }
+            },
+        );
+
+        let _rev = shell.register(root).await.unwrap();
+
+        let mut out: String<512> = String::new();
+        shell.help(&[], &mut out).await.unwrap();
+        let expected = concat!(
+            "scroll - Scroll text across the matrix\n",
+            "  scroll forward <text:str>\n",
+            "  scroll speed <ms:int 10..=1000> <unit:ms|s>\n",
+            "help [command] - List commands or show usage\n",
+            "alias [name [expansion]] - List or define aliases\n",
+            "macro [name [body]] - List or define macros taking $1..$9\n",
+            "unalias <name> - Remove an alias or macro\n",
+            "mode [text|json] - Show or set the reply format\n",
+        );
+        assert_eq!(out, expected);
+
+        let mut out: String<512> = String::new();
+        shell.help(&["scroll"], &mut out).await.unwrap();
+        let expected = concat!(
+            "scroll - Scroll text across the matrix\n",
+            "  scroll forward <text:str>\n",
+            "      Scroll text to the left\n",
+            "  scroll speed <ms:int 10..=1000> <unit:ms|s>\n",
+            "      Set the frame time\n",
+        );
+        assert_eq!(out, expected);
+
+        static REPLIES: ReplyChannel = ReplyChannel::new();
+        let command: String<256> = String::try_from("help scroll").unwrap();
+        shell
+            .send_with_reply(&command, Responder::new(&REPLIES))
+            .await
+            .unwrap();
+
+        for line in expected.lines() {
+            assert_eq!(REPLIES.try_receive().unwrap(), line);
+        }
+        assert!(REPLIES.try_receive().is_err());
+    }
+
+    async fn handler_replies_to_sender<M: RawMutex + 'static>() {
+        let shell = TestShell::<M>::new();
+
+        let root: &TestCommand<M, 0> = leak(RawRootCommand::new("Hello", "Say hello", []));
+        static REPLIES: ReplyChannel<CriticalSectionRawMutex, 1> = RawReplyChannel::new();
+
+        let mut receiver = shell.register(root).await.unwrap();
+
+        let command: String<256> = String::try_from("Hello").unwrap();
+        shell
+            .send_with_reply(&command, Responder::new(&REPLIES))
+            .await
+            .unwrap();
+
+        let request = receiver.get().await;
+        // The second reply waits for the first to be taken.
+        let ((), first) = embassy_futures::join::join(
+            async {
+                request.reply.reply("OK").await;
+                request
+                    .reply
+                    .reply_fmt(format_args!("unsupported character '{}'", '@'))
+                    .await;
+            },
+            REPLIES.receive(),
+        )
+        .await;
 
-    fn get_sub_commands(&self) -> &[SubCommand] {
-        &self.sub
+        assert_eq!(first, "OK");
+        assert_eq!(REPLIES.try_receive().unwrap(), "unsupported character '@'");
     }
 
-    fn get_channel(&self) -> &Channel<CriticalSectionRawMutex, String<256>, 5> {
-        &self.channel
+    async fn send_reaches_any_sub_command<M: RawMutex + 'static>() {
+        let shell = TestShell::<M>::new();
+
+        let root: &TestCommand<M, 2> = leak(
+            const {
+                RawRootCommand {
+                    root: "scroll",
+                    description: "Scroll text",
+                    children: [
+                        SubCommand {
+                            command: "forward",
+                            description: "Scroll left",
+                            args: &[Arg::str("text")],
+                            children: &[],
+                        },
+                        SubCommand {
+                            command: "back",
+                            description: "Scroll right",
+                            args: &[Arg::str("text")],
+                            children: &[],
+                        },
+                    ],
+                    channel: Channel::new(),
+                }
+            },
+        );
+
+        let mut rev = shell.register(root).await.unwrap();
+
+        shell.send("scroll back HI").await.unwrap();
+        let out = rev
+            .get()
+            .with_timeout(Duration::from_secs(1))
+            .await
+            .unwrap();
+
+        let text = ArgValue::Str("HI");
+        assert_eq!(out.command, parsed("scroll", &["back"], &[text]));
     }
-}
 
-pub struct Shell {
-    commands: Mutex<CriticalSectionRawMutex, Vec<&'static dyn Command, 50>>,
-}
+    async fn send_dispatch_errors<M: RawMutex + 'static>() {
+        let shell = TestShell::<M>::new();
 
-#[derive(Error, Debug, PartialEq)]
-pub enum ShellError {
-    #[error("")]
-    IncorrectArgs,
-}
+        let root: &TestCommand<M, 2> = leak(
+            const {
+                RawRootCommand {
+                    root: "Hello",
+                    description: "Say hello",
+                    children: [
+                        SubCommand {
+                            command: "world",
+                            description: "Greet the world",
+                            args: &[],
+                            children: &[],
+                        },
+                        SubCommand {
+                            command: "count",
+                            description: "Count to two numbers",
+                            args: &[Arg::int("a", 0, 10), Arg::int("b", 0, 10)],
+                            children: &[],
+                        },
+                    ],
+                    channel: Channel::new(),
+                }
+            },
+        );
 
-impl Shell {
-    pub const fn new() -> Self {
-        Self {
-            commands: Mutex::new(Vec::new()),
+        let _rev = shell.register(root).await.unwrap();
+
+        for (line, error) in [
+            ("Goodbye", ShellError::UnknownCommand),
+            ("Hello moon", ShellError::UnknownSubcommand),
+            (
+                "Hello count 1",
+                ShellError::TooFewArgs {
+                    expected: 2,
+                    got: 1,
+                },
+            ),
+            ("Hello count 1 2 3", ShellError::TooManyArgs),
+            ("Hello world 1 2 3 4 5 6 7 8 9", ShellError::TooManyTokens),
+            ("help Goodbye", ShellError::UnknownCommand),
+        ] {
+            assert_eq!(shell.send(line).await.unwrap_err(), error, "{}", line);
         }
-    }
 
-    pub async fn register(&self, command: &'static dyn Command) -> Receiver {
-        let mut commands = self.commands.lock().await;
-        let _ = commands.push(command);
-        Receiver::new(command.get_channel())
+        let line: std::string::String = "a".repeat(MAX_LINE_LEN + 1);
+        assert_eq!(
+            shell.send(&line).await.unwrap_err(),
+            ShellError::LineTooLong
+        );
     }
 
-    pub async fn send(&self, raw_command: String<256>) -> Result<(), ShellError> {
-        let split_command: Vec<String<50>, 10> = raw_command
-            .split(' ')
-            .map(|s| String::from_str(s).unwrap())
-            .collect();
+    async fn register_full_registry<M: RawMutex + 'static>() {
+        let shell = TestShell::<M>::new();
 
-        if split_command.is_empty() {
-            return Ok(());
+        fn leak_command<M: RawMutex>(root: std::string::String) -> &'static TestCommand<M, 0> {
+            leak(RawRootCommand::new(root.leak(), "", []))
         }
 
-        let root_command = split_command[0].as_str();
-        let sub_command = split_command.get(1).map_or("", |s| s.as_str());
-
-        let commands = self.commands.lock().await;
-        for command in commands.iter() {
-            if command.get_root() == root_command {
-                if split_command.len() == 1 {
-                    command.get_channel().send(raw_command.clone()).await;
-                    return Ok(());
-                }
-                for subcmd in command.get_sub_commands() {
-                    if subcmd.command == sub_command {
-                        if subcmd.args + 2 == split_command.len() {
-                            command.get_channel().send(raw_command.clone()).await;
-                            break;
-                        } else {
-                            return Err(ShellError::IncorrectArgs);
-                        }
-                    } else {
-                        return Err(ShellError::IncorrectArgs);
-                    }
-                }
-            }
+        let mut receivers = std::vec::Vec::new();
+        for i in 0..REGISTRY_LEN {
+            let command = leak_command::<M>(format!("cmd{}", i));
+            receivers.push(shell.register(command).await.unwrap());
         }
 
-        Ok(())
+        let command = leak_command::<M>("one_more".into());
+        assert_eq!(
+            shell.register(command).await.err(),
+            Some(ShellError::RegistryFull)
+        );
+
+        receivers.pop();
+        assert!(shell.register(command).await.is_ok());
     }
-}
 
-pub struct Receiver {
-    channel: &'static Channel<CriticalSectionRawMutex, String<256>, 5>,
-}
+    async fn register_duplicate_root<M: RawMutex + 'static>() {
+        let shell = TestShell::<M>::new();
 
-impl Receiver {
-    fn new(receiver: &'static Channel<CriticalSectionRawMutex, String<256>, 5>) -> Receiver {
-        Self { channel: receiver }
-    }
+        let root: &TestCommand<M, 0> = leak(RawRootCommand::new("Hello", "Say hello", []));
+        let other: &TestCommand<M, 0> = leak(RawRootCommand::new("Hello", "Say hello again", []));
+        let help: &TestCommand<M, 0> = leak(RawRootCommand::new("help", "Shadow help", []));
 
-    pub async fn get(&mut self) -> String<256> {
-        self.channel.receive().await
+        let _rev = shell.register(root).await.unwrap();
+
+        assert_eq!(
+            shell.register(other).await.err(),
+            Some(ShellError::DuplicateCommand)
+        );
+        assert_eq!(
+            shell.register(help).await.err(),
+            Some(ShellError::DuplicateCommand)
+        );
     }
-}
 
-#[cfg(test)]
-mod test {
-    use embassy_time::{Duration, WithTimeout};
+    async fn unregister_on_drop_and_reregister<M: RawMutex + 'static>() {
+        let shell = TestShell::<M>::new();
 
-    use super::*;
+        let root: &TestCommand<M, 0> = leak(RawRootCommand::new("Hello", "Say hello", []));
 
-    #[futures_test::test]
-    async fn basic_send_and_get() {
-        let shell = Shell::new();
+        let receiver = shell.register(root).await.unwrap();
+        shell.send("Hello").await.unwrap();
+        drop(receiver);
 
-        static ROOT: RootCommand<0> = RootCommand::new("Hello", []);
-        let mut receiver = shell.register(&ROOT).await;
+        assert_eq!(
+            shell.send("Hello").await.unwrap_err(),
+            ShellError::UnknownCommand
+        );
 
-        let command: String<256> = String::try_from("Hello").unwrap();
-        shell.send(command.clone()).await.unwrap();
+        let receiver = shell.register(root).await.unwrap();
+        assert!(root.channel.is_empty(), "stale request was kept");
+        receiver.unregister();
+        assert_eq!(
+            shell.send("Hello").await.unwrap_err(),
+            ShellError::UnknownCommand
+        );
+
+        let mut receiver = shell.register(root).await.unwrap();
+        shell.send("Hello").await.unwrap();
         let out = receiver
             .get()
             .with_timeout(Duration::from_secs(1))
             .await
             .unwrap();
-
-        assert_eq!(out, command);
+        assert_eq!(out.command, parsed("Hello", &[], &[]));
     }
 
-    #[futures_test::test]
-    async fn basic_send_and_get_queue() {
-        let shell = Shell::new();
-
-        static ROOT: RootCommand<0> = RootCommand {
-            root: "Hello",
-            sub: [],
-            channel: Channel::new(),
-        };
+    async fn send_quoted_argument<M: RawMutex + 'static>() {
+        let shell = TestShell::<M>::new();
 
-        let mut receiver = shell.register(&ROOT).await;
+        let root: &TestCommand<M, 1> = leak(
+            const {
+                RawRootCommand {
+                    root: "scroll",
+                    description: "Scroll text",
+                    children: [SubCommand {
+                        command: "forward",
+                        description: "Scroll left",
+                        args: &[Arg::str("text")],
+                        children: &[],
+                    }],
+                    channel: Channel::new(),
+                }
+            },
+        );
 
-        let command: String<256> = String::try_from("Hello").unwrap();
-        shell.send(command.clone()).await.unwrap();
-        shell.send(command.clone()).await.unwrap();
+        let mut rev = shell.register(root).await.unwrap();
 
-        let out = receiver
+        shell
+            .send(r#"  scroll  forward "HELLO WORLD" "#)
+            .await
+            .unwrap();
+        let out = rev
             .get()
             .with_timeout(Duration::from_secs(1))
             .await
             .unwrap();
 
-        assert_eq!(out, command);
+        let text = ArgValue::Str("HELLO WORLD");
+        assert_eq!(out.command, parsed("scroll", &["forward"], &[text]));
 
-        let out = receiver
+        assert_eq!(
+            shell.send(r#"scroll forward "HELLO"#).await.unwrap_err(),
+            ShellError::UnterminatedQuote
+        );
+        // Only the line bounds a token.
+        let long = "A".repeat(100);
+        shell
+            .send(&format!("scroll forward {}", long))
+            .await
+            .unwrap();
+        let out = rev
             .get()
             .with_timeout(Duration::from_secs(1))
             .await
             .unwrap();
+        assert_eq!(
+            out.command,
+            parsed("scroll", &["forward"], &[ArgValue::Str(&long)])
+        );
+        assert!(shell.send("   ").await.is_ok());
+    }
+
+    async fn complete_roots_and_sub_commands<M: RawMutex + 'static>() {
+        let shell = TestShell::<M>::new();
+
+        let scroll: &TestCommand<M, 2> = leak(
+            const {
+                RawRootCommand {
+                    root: "scroll",
+                    description: "Scroll text",
+                    children: [
+                        SubCommand {
+                            command: "forward",
+                            description: "Scroll left",
+                            args: &[Arg::str("text")],
+                            children: &[],
+                        },
+                        SubCommand {
+                            command: "back",
+                            description: "Scroll right",
+                            args: &[Arg::str("text")],
+                            children: &[],
+                        },
+                    ],
+                    channel: Channel::new(),
+                }
+            },
+        );
+        let show: &TestCommand<M, 0> = leak(RawRootCommand::new("show", "Show a frame", []));
+
+        let _scroll = shell.register(scroll).await.unwrap();
+        let _show = shell.register(show).await.unwrap();
+
+        async fn complete<M: RawMutex + 'static>(
+            shell: &TestShell<M>,
+            line: &str,
+        ) -> (usize, std::vec::Vec<&'static str>) {
+            let mut candidates: Vec<&'static str, 8> = Vec::new();
+            let start = shell.complete(line, &mut candidates).await;
+            (start, candidates.to_vec())
+        }
 
-        assert_eq!(out, command);
+        assert_eq!(
+            complete(&shell, "").await,
+            (
+                0,
+                vec!["scroll", "show", "help", "alias", "macro", "unalias", "mode"]
+            )
+        );
+        assert_eq!(complete(&shell, "s").await, (0, vec!["scroll", "show"]));
+        assert_eq!(
+            complete(&shell, "scroll ").await,
+            (7, vec!["forward", "back"])
+        );
+        assert_eq!(complete(&shell, "scroll  f").await, (8, vec!["forward"]));
+        assert_eq!(complete(&shell, "help sc").await, (5, vec!["scroll"]));
+        assert_eq!(complete(&shell, "scroll forward ").await, (15, vec![]));
+        assert_eq!(complete(&shell, "nope ").await, (5, vec![]));
     }
 
-    #[futures_test::test]
-    async fn basic_send_and_get_two_receivers() {
-        let shell = Shell::new();
+    static BRIGHTNESS: [SubCommand; 2] = [
+        SubCommand {
+            command: "set",
+            description: "Set the brightness",
+            args: &[Arg::int("level", 0, 9)],
+            children: &[],
+        },
+        SubCommand {
+            command: "get",
+            description: "Show the brightness",
+            args: &[],
+            children: &[],
+        },
+    ];
 
-        static ROOTA: RootCommand<0> = RootCommand {
-            root: "Hello",
-            sub: [],
-            channel: Channel::new(),
-        };
+    static FRAME: [SubCommand; 1] = [SubCommand {
+        command: "show",
+        description: "Show a frame",
+        args: &[Arg::str("hex")],
+        children: &[],
+    }];
 
-        static ROOTB: RootCommand<0> = RootCommand {
-            root: "Goodbye",
-            sub: [],
-            channel: Channel::new(),
-        };
+    async fn nested_command_tree<M: RawMutex + 'static>() {
+        let shell = TestShell::<M>::new();
 
-        let mut receiver_a = shell.register(&ROOTA).await;
-        let mut receiver_b = shell.register(&ROOTB).await;
+        let matrix: &TestCommand<M, 3> = leak(RawRootCommand::new(
+            "matrix",
+            "Drive the LED matrix",
+            [
+                SubCommand {
+                    command: "brightness",
+                    description: "Adjust the brightness",
+                    args: &[],
+                    children: &BRIGHTNESS,
+                },
+                SubCommand {
+                    command: "frame",
+                    description: "Work with frames",
+                    args: &[],
+                    children: &FRAME,
+                },
+                SubCommand {
+                    command: "text",
+                    description: "Scroll text",
+                    args: const { &[Arg::str("text")] },
+                    children: &FORWARD,
+                },
+            ],
+        ));
+        let mut rev = shell.register(matrix).await.unwrap();
 
-        let command_a: String<256> = String::try_from("Hello").unwrap();
-        let command_b: String<256> = String::try_from("Goodbye").unwrap();
+        for (line, expected) in [
+            (
+                "matrix brightness set 5",
+                parsed("matrix", &["brightness", "set"], &[ArgValue::Int(5)]),
+            ),
+            (
+                "matrix frame show 1f",
+                parsed("matrix", &["frame", "show"], &[ArgValue::Str("1f")]),
+            ),
+            ("matrix brightness", parsed("matrix", &["brightness"], &[])),
+            (
+                "matrix text forward",
+                parsed("matrix", &["text"], &[ArgValue::Str("forward")]),
+            ),
+        ] {
+            shell.send(line).await.unwrap();
+            let out = rev
+                .get()
+                .with_timeout(Duration::from_secs(1))
+                .await
+                .unwrap();
+            assert_eq!(out.command, expected, "{}", line);
+        }
+
+        for (line, error) in [
+            ("matrix brightness dim", ShellError::UnknownSubcommand),
+            (
+                "matrix brightness set",
+                ShellError::TooFewArgs {
+                    expected: 1,
+                    got: 0,
+                },
+            ),
+            ("matrix brightness set 10", ShellError::InvalidArg("level")),
+            ("matrix brightness get now", ShellError::TooManyArgs),
+            ("help matrix colour", ShellError::UnknownSubcommand),
+        ] {
+            assert_eq!(shell.send(line).await.unwrap_err(), error, "{}", line);
+        }
 
-        shell.send(command_a.clone()).await.unwrap();
-        shell.send(command_b.clone()).await.unwrap();
+        let mut text = std::string::String::new();
+        shell.help(&[], &mut text).await.unwrap();
+        assert_eq!(
+            text,
+            concat!(
+                "matrix - Drive the LED matrix\n",
+                "  matrix brightness set <level:int 0..=9>\n",
+                "  matrix brightness get\n",
+                "  matrix frame show <hex:str>\n",
+                "  matrix text <text:str>\n",
+                "help [command] - List commands or show usage\n",
+                "alias [name [expansion]] - List or define aliases\n",
+                "macro [name [body]] - List or define macros taking $1..$9\n",
+                "unalias <name> - Remove an alias or macro\n",
+                "mode [text|json] - Show or set the reply format\n",
+            )
+        );
 
-        let out_a = receiver_a
+        text.clear();
+        shell
+            .help(&["matrix", "brightness"], &mut text)
+            .await
+            .unwrap();
+        assert_eq!(
+            text,
+            concat!(
+                "matrix brightness - Adjust the brightness\n",
+                "  matrix brightness set <level:int 0..=9>\n",
+                "      Set the brightness\n",
+                "  matrix brightness get\n",
+                "      Show the brightness\n",
+            )
+        );
+
+        text.clear();
+        shell
+            .help(&["matrix", "frame", "show"], &mut text)
+            .await
+            .unwrap();
+        assert_eq!(
+            text,
+            concat!("  matrix frame show <hex:str>\n", "      Show a frame\n")
+        );
+
+        async fn complete<M: RawMutex + 'static>(
+            shell: &TestShell<M>,
+            line: &str,
+        ) -> std::vec::Vec<&'static str> {
+            let mut candidates: Vec<&'static str, 8> = Vec::new();
+            shell.complete(line, &mut candidates).await;
+            candidates.to_vec()
+        }
+
+        assert_eq!(complete(&shell, "matrix b").await, ["brightness"]);
+        assert_eq!(complete(&shell, "matrix brightness ").await, ["set", "get"]);
+        assert_eq!(complete(&shell, "help matrix f").await, ["frame"]);
+        assert!(complete(&shell, "matrix frame show ").await.is_empty());
+        assert!(complete(&shell, "matrix colour ").await.is_empty());
+        assert!(complete(&shell, "matrix text ").await.is_empty());
+    }
+
+    async fn mounted_command_nodes<M: RawMutex + 'static>() {
+        let shell = TestShell::<M>::new();
+
+        let matrix: &TestCommand<M, 1> = leak(RawRootCommand::new(
+            "matrix",
+            "Drive the LED matrix",
+            [SubCommand {
+                command: "frame",
+                description: "Work with frames",
+                args: &[],
+                children: &FRAME,
+            }],
+        ));
+        let brightness: &TestCommand<M, 1> = leak(RawRootCommand::new(
+            "matrix brightness",
+            "Adjust the brightness",
+            [SubCommand {
+                command: "set",
+                description: "Set the brightness",
+                args: const { &[Arg::int("level", 0, 9)] },
+                children: &[],
+            }],
+        ));
+        let mut matrix_rev = shell.register(matrix).await.unwrap();
+        let mut brightness_rev = shell.register(brightness).await.unwrap();
+
+        shell.send("matrix brightness set 5").await.unwrap();
+        shell.send("matrix frame show 1f").await.unwrap();
+        let out = brightness_rev.get().await;
+        assert_eq!(
+            out.command,
+            parsed("matrix brightness", &["set"], &[ArgValue::Int(5)])
+        );
+        let out = matrix_rev.get().await;
+        assert_eq!(
+            out.command,
+            parsed("matrix", &["frame", "show"], &[ArgValue::Str("1f")])
+        );
+
+        for root in ["matrix", "matrix frame", "matrix frame show", "help me"] {
+            let other: &TestCommand<M, 0> = leak(RawRootCommand::new(root, "Clash", []));
+            assert_eq!(
+                shell.register(other).await.err(),
+                Some(ShellError::DuplicateCommand),
+                "{}",
+                root
+            );
+        }
+
+        let mut text = std::string::String::new();
+        shell.help(&["matrix"], &mut text).await.unwrap();
+        assert_eq!(
+            text,
+            concat!(
+                "matrix - Drive the LED matrix\n",
+                "  matrix frame show <hex:str>\n",
+                "      Show a frame\n",
+                "matrix brightness - Adjust the brightness\n",
+                "  matrix brightness set <level:int 0..=9>\n",
+            )
+        );
+
+        let mut candidates: Vec<&'static str, 8> = Vec::new();
+        shell.complete("matrix ", &mut candidates).await;
+        assert_eq!(candidates, ["brightness", "frame"]);
+
+        drop(brightness_rev);
+        assert_eq!(
+            shell.send("matrix brightness set 5").await.unwrap_err(),
+            ShellError::UnknownSubcommand
+        );
+    }
+
+    const FORWARD: [SubCommand; 1] = [SubCommand {
+        command: "forward",
+        description: "Scroll text from right to left",
+        args: &[Arg::str("text")],
+        children: &[],
+    }];
+
+    async fn aliases_expand_before_dispatch<M: RawMutex + 'static>() {
+        let shell = TestShell::<M>::new();
+        let replies: &'static ReplyChannel = leak(ReplyChannel::new());
+
+        let scroll: &TestCommand<M, 1> =
+            leak(RawRootCommand::new("scroll", "Scroll text", FORWARD));
+        let hello: &TestCommand<M, 0> = leak(RawRootCommand::new("Hello", "Say hello", []));
+        let mut scroll_rev = shell.register(scroll).await.unwrap();
+        let mut hello_rev = shell.register(hello).await.unwrap();
+
+        shell.send("alias hi scroll forward").await.unwrap();
+        shell.send("alias greet hi HELLO; Hello").await.unwrap();
+        shell.send("greet; hi 'A B'").await.unwrap();
+
+        let text = |s: &'static str| [ArgValue::Str(s)];
+        for expected in ["HELLO", "A B"] {
+            let out = scroll_rev
+                .get()
+                .with_timeout(Duration::from_secs(1))
+                .await
+                .unwrap();
+            assert_eq!(out.command, parsed("scroll", &["forward"], &text(expected)));
+        }
+        assert!(hello_rev
             .get()
             .with_timeout(Duration::from_secs(1))
             .await
+            .is_ok());
+
+        shell
+            .send_with_reply("alias", Responder::new(replies))
+            .await
             .unwrap();
+        assert_eq!(replies.try_receive().unwrap(), "alias hi scroll forward");
+        assert_eq!(
+            replies.try_receive().unwrap(),
+            "alias greet hi HELLO; Hello"
+        );
+        assert!(replies.try_receive().is_err());
 
-        assert_eq!(out_a, command_a);
+        shell.send("alias loop loop").await.unwrap();
+        for (line, error) in [
+            ("loop", ShellError::AliasTooDeep),
+            ("alias help Hello", ShellError::DuplicateCommand),
+            ("alias scroll Hello", ShellError::DuplicateCommand),
+            ("macro macro Hello", ShellError::DuplicateCommand),
+            ("alias nope", ShellError::UnknownAlias),
+            ("unalias nope", ShellError::UnknownAlias),
+            (
+                "hi",
+                ShellError::TooFewArgs {
+                    expected: 1,
+                    got: 0,
+                },
+            ),
+        ] {
+            assert_eq!(shell.send(line).await.unwrap_err(), error, "{}", line);
+        }
 
-        let out_b = receiver_b
+        // Commands before a failing one still run.
+        assert_eq!(
+            shell.send("Hello; Hello world").await,
+            Err(ShellError::TooManyArgs)
+        );
+        assert!(hello_rev
             .get()
             .with_timeout(Duration::from_secs(1))
             .await
+            .is_ok());
+
+        // Builtins run wherever they are in a line.
+        shell.send("Hello; alias again Hello").await.unwrap();
+        shell.send("again; unalias again; Hello").await.unwrap();
+        for _ in 0..3 {
+            assert!(hello_rev
+                .get()
+                .with_timeout(Duration::from_secs(1))
+                .await
+                .is_ok());
+        }
+        assert_eq!(
+            shell.send("again").await.unwrap_err(),
+            ShellError::UnknownCommand
+        );
+
+        shell
+            .send("macro both scroll forward $2; hi $1")
+            .await
             .unwrap();
+        shell.send("both A 'B C'").await.unwrap();
+        for expected in ["B C", "A"] {
+            let out = scroll_rev
+                .get()
+                .with_timeout(Duration::from_secs(1))
+                .await
+                .unwrap();
+            assert_eq!(out.command, parsed("scroll", &["forward"], &text(expected)));
+        }
+        assert_eq!(
+            shell.send("both A").await.unwrap_err(),
+            ShellError::TooFewArgs {
+                expected: 2,
+                got: 1
+            }
+        );
+        shell
+            .send_with_reply("macro", Responder::new(replies))
+            .await
+            .unwrap();
+        assert_eq!(
+            replies.try_receive().unwrap(),
+            "macro both scroll forward $2; hi $1"
+        );
+        assert!(replies.try_receive().is_err());
 
-        assert_eq!(out_b, command_b);
+        shell.send("unalias hi").await.unwrap();
+        assert_eq!(
+            shell.send("greet").await.unwrap_err(),
+            ShellError::UnknownCommand
+        );
     }
 
-    #[futures_test::test]
-    async fn test_send_and_get_sub_cmd() {
-        let shell = Shell::new();
-
-        static ROOT: RootCommand<1> = RootCommand {
-            root: "Hello",
-            sub: [SubCommand {
-                command: "world",
-                args: 0,
-            }],
-            channel: Channel::new(),
-        };
-        let mut rev = shell.register(&ROOT).await;
+    async fn aliases_expand_as_commands_run<M: RawMutex + 'static>() {
+        let shell = TestShell::<M>::new();
+        let hello: &TestCommand<M, 0> = leak(RawRootCommand::new("Hello", "Say hello", []));
+        let mut hello_rev = shell.register(hello).await.unwrap();
 
-        let command: String<256> = String::try_from("Hello world").unwrap();
-        shell.send(command.clone()).await.unwrap();
+        // An alias defined earlier in the line is there for what follows.
+        shell.send("alias define alias hi Hello").await.unwrap();
+        shell.send("define; hi").await.unwrap();
+        assert!(hello_rev
+            .get()
+            .with_timeout(Duration::from_secs(1))
+            .await
+            .is_ok());
 
-        let out = rev
+        // One removed earlier in the line is gone for what follows.
+        assert_eq!(
+            shell.send("hi; unalias hi; hi").await,
+            Err(ShellError::UnknownCommand)
+        );
+        assert!(hello_rev
             .get()
             .with_timeout(Duration::from_secs(1))
             .await
+            .is_ok());
+        assert!(hello.channel.is_empty());
+    }
+
+    const COUNT: [SubCommand; 1] = [SubCommand {
+        command: "count",
+        description: "Queue a number",
+        args: &[Arg::int("n", 0, 100)],
+        children: &[],
+    }];
+
+    async fn fill<M: RawMutex + 'static>(shell: &TestShell<M>, root: &str) {
+        for n in 0..5 {
+            shell.send(&format!("{} count {}", root, n)).await.unwrap();
+        }
+    }
+
+    async fn drain<M: RawMutex + 'static>(receiver: &mut Receiver<'_, M>) -> std::vec::Vec<i32> {
+        let mut out = std::vec::Vec::new();
+        while let Ok(request) = receiver.get().with_timeout(Duration::from_millis(10)).await {
+            out.push(request.command.arg(0).and_then(|arg| arg.as_int()).unwrap());
+        }
+        out
+    }
+
+    async fn backpressure_reject_and_drop<M: RawMutex + 'static>() {
+        let shell = TestShell::<M>::new();
+
+        let reject: &TestCommand<M, 1> = leak(RawRootCommand::new("reject", "", COUNT));
+        let newest: &TestCommand<M, 1> = leak(RawRootCommand::new("newest", "", COUNT));
+        let oldest: &TestCommand<M, 1> = leak(RawRootCommand::new("oldest", "", COUNT));
+
+        let mut reject = shell
+            .register_with(reject, Backpressure::Reject)
+            .await
+            .unwrap();
+        let mut newest = shell
+            .register_with(newest, Backpressure::DropNewest)
+            .await
+            .unwrap();
+        let mut oldest = shell
+            .register_with(oldest, Backpressure::DropOldest)
+            .await
+            .unwrap();
+        let replies: &ReplyChannel = leak(ReplyChannel::new());
+
+        // Whichever request is discarded hears about it.
+        for (root, error) in [
+            ("reject", "error: command busy"),
+            ("newest", "error: request dropped"),
+            ("oldest", "error: request dropped"),
+        ] {
+            for n in 0..6 {
+                let line = format!("{} count {}", root, n);
+                assert_eq!(
+                    shell.send_with_reply(&line, Responder::new(replies)).await,
+                    if root == "reject" && n == 5 {
+                        Err(ShellError::Busy)
+                    } else {
+                        Ok(())
+                    }
+                );
+            }
+            assert_eq!(replies.try_receive().unwrap(), error);
+            assert!(replies.try_receive().is_err());
+        }
+
+        assert_eq!(drain(&mut reject).await, [0, 1, 2, 3, 4]);
+        assert_eq!(drain(&mut newest).await, [0, 1, 2, 3, 4]);
+        assert_eq!(drain(&mut oldest).await, [1, 2, 3, 4, 5]);
+    }
+
+    async fn stuck_handler_does_not_starve_others<M: RawMutex + 'static>() {
+        let shell = TestShell::<M>::new();
+
+        let stuck: &TestCommand<M, 1> = leak(RawRootCommand::new("stuck", "", COUNT));
+        let live: &TestCommand<M, 1> = leak(RawRootCommand::new("live", "", COUNT));
+
+        let _stuck = shell
+            .register_with(stuck, Backpressure::Reject)
+            .await
             .unwrap();
+        let mut live = shell.register(live).await.unwrap();
+
+        fill(&shell, "stuck").await;
+        assert_eq!(shell.send("stuck count 5").await, Err(ShellError::Busy));
+
+        shell.send("live count 7").await.unwrap();
+        assert_eq!(drain(&mut live).await, [7]);
+    }
+
+    async fn blocked_send_does_not_hold_registry<M: RawMutex + 'static>() {
+        let shell = TestShell::<M>::new();
+
+        let blocked: &TestCommand<M, 1> = leak(RawRootCommand::new("blocked", "", COUNT));
+        let live: &TestCommand<M, 1> = leak(RawRootCommand::new("live", "", COUNT));
+
+        let mut blocked = shell.register(blocked).await.unwrap();
+        let mut live = shell.register(live).await.unwrap();
+
+        fill(&shell, "blocked").await;
+
+        let (sent, (others, queued)) =
+            embassy_futures::join::join(shell.send("blocked count 5"), async {
+                // The blocked send is parked on the full queue while these run.
+                shell.send("live count 7").await.unwrap();
+                let others = drain(&mut live).await;
+                (others, drain(&mut blocked).await)
+            })
+            .await;
+
+        assert_eq!(sent, Ok(()));
+        assert_eq!(others, [7]);
+        assert_eq!(queued, [0, 1, 2, 3, 4, 5]);
+    }
+
+    async fn unregister_fails_blocked_send<M: RawMutex + 'static>() {
+        let shell = TestShell::<M>::new();
+
+        let blocked: &TestCommand<M, 1> = leak(RawRootCommand::new("blocked", "", COUNT));
+        let receiver = shell.register(blocked).await.unwrap();
 
-        assert_eq!(out, command);
+        fill(&shell, "blocked").await;
+
+        let (sent, ()) = embassy_futures::join::join(shell.send("blocked count 5"), async {
+            // The send is parked on the full queue when the receiver goes.
+            drop(receiver);
+        })
+        .await;
+
+        assert_eq!(sent, Err(ShellError::Unregistered));
+        assert!(
+            blocked.channel.is_empty(),
+            "request queued after unregister"
+        );
+    }
+
+    /// Move the cursor
+    #[derive(ShellCommand, Debug, PartialEq)]
+    #[shell(root = "cursor", backpressure = Reject)]
+    enum Cursor {
+        /// Jump to a cell
+        Goto {
+            x: u8,
+            #[shell(min = 0, max = 4)]
+            y: i32,
+        },
+        /// Turn to face a side
+        Face {
+            #[shell(keywords("left", "right"))]
+            side: &'static str,
+        },
+        /// Set the label
+        SetLabel { text: String<8>, visible: bool },
+        /// Return home
+        Reset,
     }
 
     #[futures_test::test]
-    async fn basic_send_and_get_with_arg() {
+    async fn derived_command_round_trip() {
         let shell = Shell::new();
+        static CURSOR: TypedCommand<Cursor> = TypedCommand::new();
+        let mut receiver = shell.register_typed(&CURSOR).await.unwrap();
 
-        static ROOT: RootCommand<1> = RootCommand {
-            root: "Hello",
-            sub: [SubCommand {
-                command: "world",
-                args: 1,
-            }],
-            channel: Channel::new(),
-        };
+        assert_eq!(<Cursor as ShellCommand>::BACKPRESSURE, Backpressure::Reject);
 
-        let mut rev = shell.register(&ROOT).await;
+        let mut received = std::vec::Vec::new();
+        for line in [
+            "cursor goto 3 4",
+            "cursor face left",
+            "cursor set-label HI on",
+            // Parses, but names no variant, so it is answered and skipped.
+            "cursor",
+            "cursor reset",
+        ] {
+            shell.send(line).await.unwrap();
+        }
+        for _ in 0..4 {
+            let request = receiver
+                .get()
+                .with_timeout(Duration::from_secs(1))
+                .await
+                .unwrap();
+            received.push(request.command);
+        }
+        assert_eq!(
+            received,
+            [
+                Cursor::Goto { x: 3, y: 4 },
+                Cursor::Face { side: "left" },
+                Cursor::SetLabel {
+                    text: String::try_from("HI").unwrap(),
+                    visible: true
+                },
+                Cursor::Reset,
+            ]
+        );
 
-        let command: String<256> = String::try_from("Hello world 5").unwrap();
-        shell.send(command.clone()).await.unwrap();
-        let out = rev
-            .get()
-            .with_timeout(Duration::from_secs(1))
+        assert_eq!(
+            shell.send("cursor goto 3 5").await,
+            Err(ShellError::InvalidArg("y"))
+        );
+        assert_eq!(
+            shell.send("cursor goto 256 0").await,
+            Err(ShellError::InvalidArg("x"))
+        );
+        assert_eq!(
+            shell.send("cursor face up").await,
+            Err(ShellError::InvalidArg("side"))
+        );
+        // Longer than the field holds.
+        assert_eq!(
+            shell.send("cursor set-label ABCDEFGHI on").await,
+            Err(ShellError::InvalidArg("text"))
+        );
+
+        let mut text = std::string::String::new();
+        shell.help(&["cursor"], &mut text).await.unwrap();
+        assert_eq!(
+            text,
+            concat!(
+                "cursor - Move the cursor\n",
+                "  cursor goto <x:int 0..=255> <y:int 0..=4>\n",
+                "      Jump to a cell\n",
+                "  cursor face <side:left|right>\n",
+                "      Turn to face a side\n",
+                "  cursor set-label <text:str> <visible:bool>\n",
+                "      Set the label\n",
+                "  cursor reset\n",
+                "      Return home\n",
+            )
+        );
+    }
+
+    #[futures_test::test]
+    async fn derived_command_on_custom_shell() {
+        use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+        // Another mutex, queue depth and line length than the defaults.
+        type SmallShell = RawShell<NoopRawMutex, 32, 2, 4>;
+        let shell = SmallShell::new();
+        let command: &RawTypedCommand<Cursor, NoopRawMutex, 2, 32> = leak(RawTypedCommand::new());
+        let mut receiver = shell.register_typed(command).await.unwrap();
+
+        shell.send("cursor goto 1 2").await.unwrap();
+        let request = receiver.get().await;
+        assert_eq!(request.command, Cursor::Goto { x: 1, y: 2 });
+
+        shell.send("cursor reset").await.unwrap();
+        shell.send("cursor reset").await.unwrap();
+        assert_eq!(shell.send("cursor reset").await, Err(ShellError::Busy));
+    }
+
+    /// Tracks an LED's state inline.
+    #[derive(Default)]
+    struct Led {
+        on: core::cell::Cell<bool>,
+    }
+
+    const LED: [SubCommand; 2] = [
+        SubCommand {
+            command: "set",
+            description: "Turn the LED on or off",
+            args: &[Arg::bool("on")],
+            children: &[],
+        },
+        SubCommand {
+            command: "status",
+            description: "Show the LED state",
+            args: &[],
+            children: &[],
+        },
+    ];
+
+    impl CommandSpec for Led {
+        fn get_root(&self) -> &'static str {
+            "led"
+        }
+
+        fn get_description(&self) -> &'static str {
+            "Drive the LED"
+        }
+
+        fn get_children(&self) -> &[SubCommand] {
+            &LED
+        }
+    }
+
+    impl AsyncCommandHandler for Led {
+        async fn handle(&self, command: ParsedCommand, reply: Responder) -> Result<(), ShellError> {
+            match command.path.as_slice() {
+                ["set"] => self.on.set(command.field(0, "on")?),
+                _ => reply.reply(if self.on.get() { "on" } else { "off" }).await,
+            }
+            Ok(())
+        }
+    }
+
+    struct AlwaysBusy;
+
+    impl CommandSpec for AlwaysBusy {
+        fn get_root(&self) -> &'static str {
+            "busy"
+        }
+
+        fn get_description(&self) -> &'static str {
+            "Fail every time"
+        }
+
+        fn get_children(&self) -> &[SubCommand] {
+            &[]
+        }
+    }
+
+    impl AsyncCommandHandler for AlwaysBusy {
+        async fn handle(
+            &self,
+            _command: ParsedCommand,
+            _reply: Responder,
+        ) -> Result<(), ShellError> {
+            Err(ShellError::Busy)
+        }
+    }
+
+    async fn inline_handlers<M: RawMutex + 'static>() {
+        let shell: RawShell<M, MAX_LINE_LEN, QUEUE_DEPTH, REGISTRY_LEN, _> =
+            RawShell::with_handlers((Led::default(), AlwaysBusy));
+        let replies: &ReplyChannel = leak(ReplyChannel::new());
+
+        shell
+            .send_with_reply("led set on; led status", Responder::new(replies))
             .await
             .unwrap();
+        assert!(shell.handlers.0.on.get());
+        assert_eq!(replies.try_receive().unwrap(), "on");
+
+        assert_eq!(
+            shell.send("led set maybe").await,
+            Err(ShellError::InvalidArg("on"))
+        );
+        assert_eq!(
+            shell.send("led blink").await,
+            Err(ShellError::UnknownSubcommand)
+        );
+        assert_eq!(shell.send("busy").await, Err(ShellError::Busy));
+
+        let led: &TestCommand<M, 0> = leak(RawRootCommand::new("led", "Shadow the LED", []));
+        assert_eq!(
+            shell.register(led).await.err(),
+            Some(ShellError::DuplicateCommand)
+        );
+        assert_eq!(
+            shell.send("alias led busy").await,
+            Err(ShellError::DuplicateCommand)
+        );
+
+        let mut candidates: Vec<&'static str, 8> = Vec::new();
+        shell.complete("led s", &mut candidates).await;
+        assert_eq!(candidates, ["set", "status"]);
 
-        assert_eq!(out, command);
+        let mut out: String<512> = String::new();
+        shell.help(&[], &mut out).await.unwrap();
+        assert!(out.starts_with(concat!(
+            "led - Drive the LED\n",
+            "  led set <on:bool>\n",
+            "  led status\n",
+            "busy - Fail every time\n",
+            "help ",
+        )));
+
+        let mut out: String<512> = String::new();
+        shell.help(&["led", "set"], &mut out).await.unwrap();
+        assert_eq!(out, "  led set <on:bool>\n      Turn the LED on or off\n");
     }
 
-    #[futures_test::test]
-    async fn basic_send_and_get_sub_cmd_with_too_many_args() {
-        let shell = Shell::new();
+    async fn json_mode_replies<M: RawMutex + 'static>() {
+        let shell: RawShell<M, MAX_LINE_LEN, QUEUE_DEPTH, REGISTRY_LEN, _> =
+            RawShell::with_handlers((Led::default(),));
+        let replies: &ReplyChannel = leak(ReplyChannel::new());
+        let reply = Responder::new(replies);
 
-        static ROOT: RootCommand<1> = RootCommand {
-            root: "Hello",
-            sub: [SubCommand {
-                command: "world",
-                args: 0,
-            }],
-            channel: Channel::new(),
+        shell.send_with_reply("mode", reply).await.unwrap();
+        assert_eq!(replies.try_receive().unwrap(), "text");
+        assert_eq!(
+            shell.send_with_reply("led blink", reply).await,
+            Err(ShellError::UnknownSubcommand)
+        );
+        assert_eq!(replies.try_receive().unwrap(), "error: unknown subcommand");
+
+        shell.send_with_reply("mode json", reply).await.unwrap();
+        assert_eq!(replies.mode(), OutputMode::Json);
+        assert!(replies.try_receive().is_err());
+
+        // One object per request, whatever it wrote.
+        shell.send_with_reply("#7 led set on", reply).await.unwrap();
+        assert_eq!(
+            replies.try_receive().unwrap(),
+            r#"{"id":7,"status":"ok","error":null,"data":""}"#
+        );
+        shell
+            .send_with_reply("#8 led status; led status", reply)
+            .await
+            .unwrap();
+        assert_eq!(
+            replies.try_receive().unwrap(),
+            r#"{"id":8,"status":"ok","error":null,"data":"on\non"}"#
+        );
+        shell.send_with_reply("help led", reply).await.unwrap();
+        let help = replies.try_receive().unwrap();
+        assert!(help.starts_with(r#"{"id":null,"status":"ok","error":null,"data":"led - Drive"#));
+        assert!(replies.try_receive().is_err());
+
+        shell
+            .send_with_reply("led status; led blink", reply)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            replies.try_receive().unwrap(),
+            r#"{"id":null,"status":"error","error":"unknown subcommand","data":"on"}"#
+        );
+        shell
+            .send_with_reply("#x led status", reply)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            replies.try_receive().unwrap(),
+            r#"{"id":null,"status":"error","error":"invalid value for argument 'id'","data":""}"#
+        );
+        assert_eq!(
+            shell.send("mode yaml").await,
+            Err(ShellError::InvalidArg("mode"))
+        );
+
+        // Long data is cut short so the object still fits on a line.
+        let json = reply.with_mode(OutputMode::Json);
+        json.start();
+        json.reply(&"\"".repeat(MAX_REPLY_LEN)).await;
+        json.complete().await;
+        let line = replies.try_receive().unwrap();
+        assert!(line.starts_with(r#"{"id":null,"status":"ok","error":null,"data":"\"\"#));
+        assert!(line.ends_with(r#"\""}"#));
+
+        // The mode belongs to the channel, not the shell.
+        let other: &ReplyChannel = leak(ReplyChannel::new());
+        shell
+            .send_with_reply("mode", Responder::new(other))
+            .await
+            .unwrap();
+        assert_eq!(other.try_receive().unwrap(), "text");
+        // Leaving JSON mode is still answered in JSON.
+        shell.send_with_reply("mode text", reply).await.unwrap();
+        assert_eq!(replies.mode(), OutputMode::Text);
+        assert_eq!(
+            replies.try_receive().unwrap(),
+            r#"{"id":null,"status":"ok","error":null,"data":""}"#
+        );
+    }
+
+    async fn json_mode_waits_for_handlers<M: RawMutex + 'static>() {
+        let shell = TestShell::<M>::new();
+        let root: &TestCommand<M, 0> = leak(RawRootCommand::new("Hello", "Say hello", []));
+        let mut receiver = shell.register(root).await.unwrap();
+        let replies: &ReplyChannel = leak(ReplyChannel::new());
+        replies.set_mode(OutputMode::Json);
+
+        // The object waits for the handler to ask for the next request.
+        let handler = async {
+            let request = receiver.get().await;
+            request.reply.reply("hi").await;
+            request.reply.reply("there").await;
+            assert!(replies.try_receive().is_err());
+            receiver.get().await;
         };
+        let send = shell.send_with_reply("#3 Hello", Responder::new(replies));
+        match select(handler, send).await {
+            Either::First(_) => panic!("handler ended"),
+            Either::Second(result) => result.unwrap(),
+        }
+        assert_eq!(
+            replies.try_receive().unwrap(),
+            r#"{"id":3,"status":"ok","error":null,"data":"hi\nthere"}"#
+        );
 
-        let _ = shell.register(&ROOT).await;
+        // Dropping the receiver finishes what it held.
+        let send = shell.send_with_reply("Hello", Responder::new(replies));
+        let handler = async {
+            receiver.get().await.reply.error("gone").await;
+            drop(receiver);
+        };
+        let (result, ()) = embassy_futures::join::join(send, handler).await;
+        result.unwrap();
+        assert_eq!(
+            replies.try_receive().unwrap(),
+            r#"{"id":null,"status":"error","error":"gone","data":""}"#
+        );
+    }
 
-        let command: String<256> = String::try_from("Hello world 5").unwrap();
-        let out = shell.send(command.clone()).await.unwrap_err();
+    async fn tagged_replies_keep_id<M: RawMutex + 'static>() {
+        let shell: RawShell<M, MAX_LINE_LEN, QUEUE_DEPTH, REGISTRY_LEN, _> =
+            RawShell::with_handlers((Led::default(),));
+        let replies: &TaggedReplyChannel = leak(TaggedReplyChannel::new());
+        let reply = Responder::tagged(replies);
 
-        assert_eq!(out, ShellError::IncorrectArgs);
+        // Tagged replies are left unformatted, so there is no mode to pick.
+        assert_eq!(
+            shell.send_with_reply("mode json", reply).await,
+            Err(ShellError::InvalidArg("mode"))
+        );
+        replies.try_receive().unwrap();
+        replies.try_receive().unwrap();
+
+        // Each request ends with a final reply, even when it wrote nothing.
+        let done = |id, error| TaggedReply {
+            id,
+            error,
+            done: true,
+            line: String::new(),
+        };
+        shell.send_with_reply("#5 led status", reply).await.unwrap();
+        assert_eq!(
+            replies.try_receive().unwrap(),
+            TaggedReply {
+                id: Some(5),
+                error: false,
+                done: false,
+                line: String::try_from("off").unwrap(),
+            }
+        );
+        assert_eq!(replies.try_receive().unwrap(), done(Some(5), false));
+        shell.send_with_reply("#6 led set on", reply).await.unwrap();
+        assert_eq!(replies.try_receive().unwrap(), done(Some(6), false));
+        shell.send_with_reply("led blink", reply).await.unwrap_err();
+        assert_eq!(
+            replies.try_receive().unwrap(),
+            TaggedReply {
+                id: None,
+                error: true,
+                done: false,
+                line: String::try_from("unknown subcommand").unwrap(),
+            }
+        );
+        assert_eq!(replies.try_receive().unwrap(), done(None, true));
+        assert!(replies.try_receive().is_err());
     }
 }
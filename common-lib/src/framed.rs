@@ -0,0 +1,570 @@
+use core::fmt::{self, Write};
+
+use embassy_time::{with_timeout, Duration};
+use heapless::{String, Vec};
+use shell_protocol::{
+    encode, CodecError, Command, Direction, FrameDecoder, Reply, Request, Response, Status,
+    MAX_FRAME_LEN,
+};
+use thiserror::Error;
+
+use crate::cli::{ReplyChannel, Responder, TaggedReply, TaggedReplyChannel, ID_PREFIX};
+use crate::line_editor::Completer;
+use crate::prelude::*;
+use crate::transport::{read_recovering, Console, ConsoleError, Transport, MAX_LINE_LEN};
+use crate::uarte::{UarteRx, UarteRxError, UarteTx, UarteTxError};
+
+#[derive(Error, Debug)]
+pub enum FramedError {
+    #[error("transmit failed")]
+    Tx(#[from] UarteTxError),
+    #[error("encoding failed: {0}")]
+    Codec(#[from] CodecError),
+}
+
+/// Turns [`shell_protocol`] request frames into shell lines, a byte at a
+/// time.
+///
+/// Lines are prefixed with the request id, so a [`Responder::tagged`] on
+/// `replies` tags the replies with it. Frames that can't be used are
+/// answered on `replies` directly, with an error and a final status.
+///
+/// [`Responder::tagged`]: crate::cli::Responder::tagged
+pub struct FrameReader {
+    decoder: FrameDecoder,
+    replies: &'static TaggedReplyChannel,
+}
+
+impl FrameReader {
+    pub const fn new(replies: &'static TaggedReplyChannel) -> Self {
+        Self {
+            decoder: FrameDecoder::new(),
+            replies,
+        }
+    }
+
+    /// Feeds one byte, returning the shell line once a request is complete.
+    pub async fn push(&mut self, byte: u8) -> Option<String<MAX_LINE_LEN>> {
+        let (id, error) = match self.decoder.push::<Request>(byte)? {
+            Ok(request) => match command_line(&request) {
+                Ok(line) => return Some(line),
+                Err(_) => (Some(request.id), "line too long"),
+            },
+            Err(CodecError::FrameTooLong) => (None, "frame too long"),
+            Err(CodecError::Checksum) => (None, "checksum mismatch"),
+            Err(_) => (None, "malformed frame"),
+        };
+        warn!("dropped frame: {}", error);
+        let reply = TaggedReply {
+            id,
+            error: true,
+            done: false,
+            line: String::try_from(error).unwrap_or_default(),
+        };
+        self.replies.send(reply).await;
+        let done = TaggedReply {
+            id,
+            error: true,
+            done: true,
+            line: String::new(),
+        };
+        self.replies.send(done).await;
+        None
+    }
+
+    /// Whether no part of a frame has arrived since the last delimiter.
+    pub fn is_empty(&self) -> bool {
+        self.decoder.is_empty()
+    }
+
+    /// Drops any part of a frame received so far.
+    pub fn reset(&mut self) {
+        self.decoder.reset();
+    }
+}
+
+/// A [`Transport`] for automation over a binary link, reading requests
+/// through a [`FrameReader`].
+pub struct FramedTransport<R: UarteRx> {
+    reader: FrameReader,
+    input: [u8; 64],
+    start: usize,
+    end: usize,
+    rx: R,
+}
+
+impl<R: UarteRx> FramedTransport<R> {
+    pub fn new(uarte_rx: R, replies: &'static TaggedReplyChannel) -> Self {
+        Self {
+            reader: FrameReader::new(replies),
+            input: [0; 64],
+            start: 0,
+            end: 0,
+            rx: uarte_rx,
+        }
+    }
+}
+
+impl<R: UarteRx> Transport for FramedTransport<R> {
+    type Error = UarteRxError;
+    async fn next_line(&mut self) -> Result<Option<String<MAX_LINE_LEN>>, Self::Error> {
+        if self.start == self.end {
+            self.start = 0;
+            self.end = match read_recovering(&mut self.rx, &mut self.input).await? {
+                Some(size) => size,
+                // Whatever was underway when the line failed is lost.
+                None => {
+                    self.reader.reset();
+                    0
+                }
+            };
+        }
+
+        while self.start < self.end {
+            let byte = self.input[self.start];
+            self.start += 1;
+            if let Some(line) = self.reader.push(byte).await {
+                return Ok(Some(line));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// How long a frame may stall before the rest of it is given up on.
+pub const FRAME_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Shares one link between a human at a terminal and a program speaking
+/// [`shell_protocol`] frames.
+///
+/// Input is text for an [`InteractiveTransport`] until a zero byte, which
+/// starts a frame; frames from [`encode`] lead with one. Further zero bytes
+/// before the frame are skipped. Once
+/// the frame ends, or stalls for [`FRAME_TIMEOUT`], input is text again.
+/// Frames are not echoed and leave a half typed line as it was.
+///
+/// Each request comes with a [`Responder`] for its protocol: text lines
+/// reply on `replies`, frames on `tagged`.
+///
+/// [`InteractiveTransport`]: crate::transport::InteractiveTransport
+pub struct MuxTransport<R: UarteRx, W: UarteTx, C: Completer, const H: usize> {
+    console: Console<W, C, H>,
+    frames: FrameReader,
+    framing: bool,
+    input: [u8; 64],
+    start: usize,
+    end: usize,
+    rx: R,
+    replies: &'static ReplyChannel,
+    tagged: &'static TaggedReplyChannel,
+}
+
+impl<R: UarteRx, W: UarteTx, C: Completer, const H: usize> MuxTransport<R, W, C, H> {
+    pub fn new(
+        uarte_rx: R,
+        uarte_tx: W,
+        completer: C,
+        replies: &'static ReplyChannel,
+        tagged: &'static TaggedReplyChannel,
+    ) -> Self {
+        Self {
+            console: Console::new(uarte_tx, completer),
+            frames: FrameReader::new(tagged),
+            framing: false,
+            input: [0; 64],
+            start: 0,
+            end: 0,
+            rx: uarte_rx,
+            replies,
+            tagged,
+        }
+    }
+
+    /// Reads until a line or frame is complete, as for
+    /// [`Transport::next_line`], with the [`Responder`] its replies go to.
+    pub async fn next_request(
+        &mut self,
+    ) -> Result<Option<(String<MAX_LINE_LEN>, Responder)>, ConsoleError> {
+        let mut echo = Vec::new();
+        self.console.prompt(&mut echo);
+
+        if self.start == self.end {
+            self.console.flush(&mut echo).await?;
+            self.start = 0;
+            let read = read_recovering(&mut self.rx, &mut self.input);
+            let size = if self.framing {
+                match with_timeout(FRAME_TIMEOUT, read).await {
+                    Ok(size) => size?,
+                    Err(_) => {
+                        warn!("dropped incomplete frame");
+                        self.frames.reset();
+                        self.framing = false;
+                        Some(0)
+                    }
+                }
+            } else {
+                read.await?
+            };
+            self.end = match size {
+                Some(size) => size,
+                // Whatever was underway when the line failed is lost.
+                None if self.framing => {
+                    self.frames.reset();
+                    self.framing = false;
+                    0
+                }
+                None => {
+                    self.console.cancel(&mut echo);
+                    0
+                }
+            };
+        }
+
+        let mut line = None;
+        let mut request = None;
+        while self.start < self.end && line.is_none() && request.is_none() {
+            let byte = self.input[self.start];
+            self.start += 1;
+            if self.framing {
+                let ends = byte == 0 && !self.frames.is_empty();
+                request = self.frames.push(byte).await;
+                self.framing = !ends;
+            } else if byte == 0 {
+                self.framing = true;
+            } else {
+                line = self.console.feed(byte, &mut echo).await?;
+            }
+        }
+
+        if let Some(request) = request {
+            self.console.flush(&mut echo).await?;
+            return Ok(Some((request, Responder::tagged(self.tagged))));
+        }
+        let line = self.console.finish(line, &mut echo).await?;
+        Ok(line.map(|line| (line, Responder::new(self.replies))))
+    }
+}
+
+/// Encodes `reply` as a [`Response`] frame and writes it to `tx`.
+pub async fn write_reply<W: UarteTx>(tx: &mut W, reply: &TaggedReply) -> Result<(), FramedError> {
+    let line = reply.line.clone();
+    let response = Response {
+        id: reply.id,
+        reply: match (reply.done, reply.error) {
+            (true, false) => Reply::Status(Status::Ok),
+            (true, true) => Reply::Status(Status::Error),
+            (false, true) => Reply::Error(line),
+            (false, false) => Reply::Line(line),
+        },
+    };
+    let mut frame = [0; MAX_FRAME_LEN];
+    let len = encode(&response, &mut frame)?;
+    tx.write(&frame[..len]).await?;
+    Ok(())
+}
+
+/// The shell line that carries out `request`.
+fn command_line(request: &Request) -> Result<String<MAX_LINE_LEN>, fmt::Error> {
+    let mut line = String::new();
+    write!(line, "{}{} ", ID_PREFIX, request.id)?;
+    match &request.command {
+        Command::Scroll { text, direction } => {
+            let direction = match direction {
+                Direction::Forward => "forward",
+                Direction::Back => "back",
+            };
+            write!(line, "scroll {} \"", direction)?;
+            for c in text.chars() {
+                if matches!(c, '"' | '\\') {
+                    line.write_char('\\')?;
+                }
+                line.write_char(c)?;
+            }
+            line.write_char('"')?;
+        }
+        Command::SetFrame { rows } => {
+            line.write_str("frame set ")?;
+            for row in rows {
+                write!(line, "{:02x}", row)?;
+            }
+        }
+        Command::Status => line.write_str("status")?,
+        Command::Shell { line: command } => line.write_str(command)?,
+    }
+    Ok(line)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+
+    use embassy_time::Timer;
+    use shell_protocol::{decode, MAX_SHELL_LINE_LEN};
+
+    use super::*;
+    use crate::uarte::{MockUarteRx, UarteRxError};
+    use crate::uarte_mock::capture_tx;
+
+    fn leak<T>(value: T) -> &'static T {
+        Box::leak(Box::new(value))
+    }
+
+    fn frame(request: &Request) -> std::vec::Vec<u8> {
+        let mut out = [0; MAX_FRAME_LEN];
+        let len = encode(request, &mut out).unwrap();
+        out[..len].to_vec()
+    }
+
+    /// Hands out each chunk of input after its delay, then nothing. A chunk
+    /// larger than the buffer is handed out over several reads.
+    struct PacedRx(VecDeque<(Duration, std::vec::Vec<u8>)>);
+
+    impl UarteRx for PacedRx {
+        async fn read(&mut self, buffer: &mut [u8]) -> Result<(), UarteRxError> {
+            let mut filled = 0;
+            while filled < buffer.len() {
+                filled += self.read_until_idle(&mut buffer[filled..]).await?;
+            }
+            Ok(())
+        }
+
+        async fn read_until_idle(&mut self, buffer: &mut [u8]) -> Result<usize, UarteRxError> {
+            let Some(&(delay, _)) = self.0.front() else {
+                return core::future::pending().await;
+            };
+            Timer::after(delay).await;
+            let (delay, chunk) = self.0.front_mut().unwrap();
+            let len = chunk.len().min(buffer.len());
+            buffer[..len].copy_from_slice(&chunk[..len]);
+            chunk.drain(..len);
+            *delay = Duration::from_ticks(0);
+            if chunk.is_empty() {
+                self.0.pop_front();
+            }
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn test_command_lines() {
+        let line = |id, command| command_line(&Request { id, command }).unwrap();
+
+        assert_eq!(
+            line(
+                1,
+                Command::Scroll {
+                    text: String::try_from(r#"SAY "HI" \o/"#).unwrap(),
+                    direction: Direction::Back,
+                }
+            ),
+            r#"#1 scroll back "SAY \"HI\" \\o/""#
+        );
+        assert_eq!(
+            line(
+                2,
+                Command::SetFrame {
+                    rows: [0x1f, 0x11, 0x00, 0x0a, 0x1f]
+                }
+            ),
+            "#2 frame set 1f11000a1f"
+        );
+        assert_eq!(line(3, Command::Status), "#3 status");
+        assert_eq!(
+            line(
+                4,
+                Command::Shell {
+                    line: String::try_from("help scroll").unwrap()
+                }
+            ),
+            "#4 help scroll"
+        );
+        // The longest line still fits with the longest id.
+        let long = "x".repeat(MAX_SHELL_LINE_LEN);
+        let tagged = line(
+            u32::MAX,
+            Command::Shell {
+                line: String::try_from(long.as_str()).unwrap(),
+            },
+        );
+        assert_eq!(tagged, format!("#{} {}", u32::MAX, long).as_str());
+    }
+
+    #[futures_test::test]
+    async fn test_framed_transport_reads_requests() {
+        let mut input = frame(&Request {
+            id: 7,
+            command: Command::Status,
+        });
+        let mut corrupt = frame(&Request {
+            id: 8,
+            command: Command::Status,
+        });
+        corrupt[3] ^= 0x01;
+        input.extend(corrupt);
+        input.extend(frame(&Request {
+            id: 9,
+            command: Command::Shell {
+                line: String::try_from("Hello").unwrap(),
+            },
+        }));
+
+        let mut mock = MockUarteRx::new();
+        mock.expect_read_until_idle()
+            .times(1)
+            .returning(move |buf| {
+                buf[..input.len()].copy_from_slice(&input);
+                Ok(input.len())
+            });
+
+        let replies: &TaggedReplyChannel = leak(TaggedReplyChannel::new());
+        let mut transport = FramedTransport::new(mock, replies);
+
+        assert_eq!(transport.next_line().await.unwrap().unwrap(), "#7 status");
+        assert_eq!(transport.next_line().await.unwrap().unwrap(), "#9 Hello");
+        assert_eq!(
+            replies.try_receive().unwrap(),
+            TaggedReply {
+                id: None,
+                error: true,
+                done: false,
+                line: String::try_from("checksum mismatch").unwrap(),
+            }
+        );
+        assert!(replies.try_receive().unwrap().done);
+    }
+
+    #[futures_test::test]
+    async fn test_framed_transport_drops_frame_on_rx_error() {
+        let broken = frame(&Request {
+            id: 1,
+            command: Command::Status,
+        });
+        let next = frame(&Request {
+            id: 2,
+            command: Command::Status,
+        });
+
+        let mut mock = MockUarteRx::new();
+        mock.expect_read_until_idle()
+            .times(1)
+            .returning(move |buf| {
+                buf[..4].copy_from_slice(&broken[..4]);
+                Ok(4)
+            });
+        mock.expect_read_until_idle()
+            .times(1)
+            .returning(|_| Err(UarteRxError::Overrun));
+        mock.expect_read_until_idle()
+            .times(1)
+            .returning(move |buf| {
+                buf[..next.len()].copy_from_slice(&next);
+                Ok(next.len())
+            });
+
+        let replies: &TaggedReplyChannel = leak(TaggedReplyChannel::new());
+        let mut transport = FramedTransport::new(mock, replies);
+
+        assert!(transport.next_line().await.unwrap().is_none());
+        assert!(transport.next_line().await.unwrap().is_none());
+        assert_eq!(transport.next_line().await.unwrap().unwrap(), "#2 status");
+        assert!(replies.try_receive().is_err());
+    }
+
+    #[futures_test::test]
+    async fn test_write_reply() {
+        let (mut tx, written) = capture_tx();
+
+        let reply = TaggedReply {
+            id: Some(3),
+            error: true,
+            done: false,
+            line: String::try_from("unknown command").unwrap(),
+        };
+        write_reply(&mut tx, &reply).await.unwrap();
+        let done = TaggedReply {
+            done: true,
+            line: String::new(),
+            ..reply
+        };
+        write_reply(&mut tx, &done).await.unwrap();
+
+        let mut bytes = written.bytes();
+        let responses: std::vec::Vec<_> = bytes
+            .split_mut(|&b| b == 0)
+            .filter(|frame| !frame.is_empty())
+            .map(|frame| decode::<Response>(frame).unwrap())
+            .collect();
+        assert_eq!(
+            responses,
+            [
+                Response {
+                    id: Some(3),
+                    reply: Reply::Error(String::try_from("unknown command").unwrap()),
+                },
+                Response {
+                    id: Some(3),
+                    reply: Reply::Status(Status::Error),
+                },
+            ]
+        );
+    }
+
+    #[futures_test::test]
+    async fn test_mux_routes_text_and_frames() {
+        // The frame's leading zero is enough to tell it from text, and a
+        // spare one before it is skipped.
+        let mut input = b"sta".to_vec();
+        input.push(0);
+        input.extend(frame(&Request {
+            id: 4,
+            command: Command::Status,
+        }));
+        input.extend(b"tus\r");
+        let rx = PacedRx(VecDeque::from([(Duration::from_ticks(0), input)]));
+
+        let (tx, written) = capture_tx();
+        let replies: &ReplyChannel = leak(ReplyChannel::new());
+        let tagged: &TaggedReplyChannel = leak(TaggedReplyChannel::new());
+        let mut transport: MuxTransport<_, _, _, 4> =
+            MuxTransport::new(rx, tx, (), replies, tagged);
+
+        let (line, reply) = transport.next_request().await.unwrap().unwrap();
+        assert_eq!(line, "#4 status");
+        reply.reply("uptime 1s").await;
+        assert_eq!(tagged.try_receive().unwrap().line, "uptime 1s");
+
+        // The frame neither echoed nor broke up the typed line.
+        let (line, reply) = transport.next_request().await.unwrap().unwrap();
+        assert_eq!(line, "status");
+        reply.reply("uptime 2s").await;
+        assert_eq!(replies.try_receive().unwrap(), "uptime 2s");
+
+        assert_eq!(written.text(), "> status\r\n");
+    }
+
+    #[futures_test::test]
+    async fn test_mux_drops_stalled_frame() {
+        let stalled = frame(&Request {
+            id: 1,
+            command: Command::Status,
+        });
+        let first = stalled[..4].to_vec();
+        let rx = PacedRx(VecDeque::from([
+            (Duration::from_ticks(0), first),
+            (FRAME_TIMEOUT * 2, b"Hello\r".to_vec()),
+        ]));
+
+        let (tx, _) = capture_tx();
+        let replies: &ReplyChannel = leak(ReplyChannel::new());
+        let tagged: &TaggedReplyChannel = leak(TaggedReplyChannel::new());
+        let mut transport: MuxTransport<_, _, _, 4> =
+            MuxTransport::new(rx, tx, (), replies, tagged);
+
+        assert!(transport.next_request().await.unwrap().is_none());
+        assert!(transport.next_request().await.unwrap().is_none());
+
+        let (line, _) = transport.next_request().await.unwrap().unwrap();
+        assert_eq!(line, "Hello");
+        assert!(tagged.try_receive().is_err());
+    }
+}
@@ -0,0 +1,87 @@
+use heapless::{Deque, String};
+
+/// The last `N` submitted lines of up to `L` bytes, oldest first.
+pub struct History<const N: usize, const L: usize> {
+    lines: Deque<String<L>, N>,
+}
+
+impl<const N: usize, const L: usize> Default for History<N, L> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize, const L: usize> History<N, L> {
+    pub const fn new() -> Self {
+        Self {
+            lines: Deque::new(),
+        }
+    }
+
+    /// Records a line, evicting the oldest once full. Blank lines and
+    /// repeats of the newest entry are skipped.
+    pub fn push(&mut self, line: &str) {
+        if line.trim().is_empty() || self.newest(0).is_some_and(|newest| newest == line) {
+            return;
+        }
+        let Ok(line) = String::try_from(line) else {
+            return;
+        };
+        if self.lines.is_full() {
+            self.lines.pop_front();
+        }
+        let _ = self.lines.push_back(line);
+    }
+
+    /// The entry `age` submissions ago, where 0 is the most recent.
+    pub fn newest(&self, age: usize) -> Option<&str> {
+        let index = self.lines.len().checked_sub(age + 1)?;
+        self.lines.iter().nth(index).map(|line| line.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.lines.iter().map(|line| line.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_history_evicts_oldest() {
+        let mut history: History<3, 16> = History::new();
+        for line in ["a", "b", "c", "d"] {
+            history.push(line);
+        }
+
+        assert_eq!(
+            history.iter().collect::<std::vec::Vec<_>>(),
+            ["b", "c", "d"]
+        );
+        assert_eq!(history.newest(0), Some("d"));
+        assert_eq!(history.newest(2), Some("b"));
+        assert_eq!(history.newest(3), None);
+    }
+
+    #[test]
+    fn test_history_skips_blank_and_repeated() {
+        let mut history: History<3, 16> = History::new();
+        for line in ["a", "", "  ", "a", "b", "a"] {
+            history.push(line);
+        }
+
+        assert_eq!(
+            history.iter().collect::<std::vec::Vec<_>>(),
+            ["a", "b", "a"]
+        );
+    }
+}
@@ -0,0 +1,343 @@
+//! The UARTE traits on a host, for running the shell off the board.
+//!
+//! [`HostRx`] and [`HostTx`] wrap any byte stream, doing the blocking I/O
+//! on threads of their own. A [`Pty`] provides one that terminal programs
+//! such as picocom can open like a serial port.
+
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread;
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pipe::Pipe;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration};
+
+use crate::uarte::{UarteRx, UarteRxError, UarteTx, UarteTxError};
+
+/// How long the line must be quiet for [`HostRx::read_until_idle`] to
+/// return, unless changed with [`HostRx::with_idle_timeout`].
+pub const IDLE_TIMEOUT: Duration = Duration::from_millis(5);
+
+type HostPipe = Pipe<CriticalSectionRawMutex, 256>;
+
+/// Input from a blocking reader, which is read on a thread of its own.
+///
+/// Clones share the reader, each byte going to whichever reads first.
+#[derive(Clone)]
+pub struct HostRx {
+    pipe: Arc<HostPipe>,
+    idle: Duration,
+}
+
+impl HostRx {
+    pub fn new(mut reader: impl Read + Send + 'static) -> Self {
+        let pipe = Arc::new(HostPipe::new());
+        let sink = pipe.clone();
+        thread::spawn(move || {
+            let mut buf = [0; 64];
+            // Ends when the reader does.
+            while let Ok(size @ 1..) = reader.read(&mut buf) {
+                block_on(sink.write_all(&buf[..size]));
+            }
+        });
+        Self {
+            pipe,
+            idle: IDLE_TIMEOUT,
+        }
+    }
+
+    pub fn with_idle_timeout(mut self, idle: Duration) -> Self {
+        self.idle = idle;
+        self
+    }
+}
+
+impl UarteRx for HostRx {
+    async fn read(&mut self, mut buffer: &mut [u8]) -> Result<(), UarteRxError> {
+        while !buffer.is_empty() {
+            let size = self.pipe.read(buffer).await;
+            buffer = &mut buffer[size..];
+        }
+        Ok(())
+    }
+
+    /// Waits for input, then reads until `buffer` is full or nothing more
+    /// arrives within the idle timeout.
+    async fn read_until_idle(&mut self, buffer: &mut [u8]) -> Result<usize, UarteRxError> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        let mut size = self.pipe.read(buffer).await;
+        while size < buffer.len() {
+            match with_timeout(self.idle, self.pipe.read(&mut buffer[size..])).await {
+                Ok(more) => size += more,
+                Err(_) => break,
+            }
+        }
+        Ok(size)
+    }
+}
+
+/// What transmitters share with the thread writing for them.
+struct TxLink {
+    pipe: HostPipe,
+    /// Signalled once every transmitter is gone.
+    closed: Signal<CriticalSectionRawMutex, ()>,
+    failed: AtomicBool,
+}
+
+/// Held by transmitters alone, so the thread hears when the last one goes.
+struct TxHandle(Arc<TxLink>);
+
+impl Drop for TxHandle {
+    fn drop(&mut self) {
+        self.0.closed.signal(());
+    }
+}
+
+/// Output to a blocking writer, which is written on a thread of its own.
+///
+/// Writes return once the bytes are queued for the thread. Once the writer
+/// fails, every later write fails too. Clones share the writer.
+#[derive(Clone)]
+pub struct HostTx {
+    handle: Arc<TxHandle>,
+}
+
+impl HostTx {
+    pub fn new(mut writer: impl Write + Send + 'static) -> Self {
+        let link = Arc::new(TxLink {
+            pipe: HostPipe::new(),
+            closed: Signal::new(),
+            failed: AtomicBool::new(false),
+        });
+        let source = link.clone();
+        thread::spawn(move || {
+            let mut buf = [0; 64];
+            let mut write = |bytes: &[u8]| {
+                // Once failed, bytes are dropped so writers aren't left
+                // waiting on a full pipe.
+                if !source.failed.load(Ordering::Relaxed)
+                    && writer
+                        .write_all(bytes)
+                        .and_then(|()| writer.flush())
+                        .is_err()
+                {
+                    source.failed.store(true, Ordering::Relaxed);
+                }
+            };
+            while let Either::First(size) =
+                block_on(select(source.pipe.read(&mut buf), source.closed.wait()))
+            {
+                write(&buf[..size]);
+            }
+            // Every transmitter is gone; write out what they left.
+            while let Ok(size) = source.pipe.try_read(&mut buf) {
+                write(&buf[..size]);
+            }
+        });
+        Self {
+            handle: Arc::new(TxHandle(link)),
+        }
+    }
+}
+
+impl UarteTx for HostTx {
+    async fn write(&mut self, buffer: &[u8]) -> Result<(), UarteTxError> {
+        let link = &self.handle.0;
+        if link.failed.load(Ordering::Relaxed) {
+            return Err(UarteTxError::Other);
+        }
+        link.pipe.write_all(buffer).await;
+        Ok(())
+    }
+
+    async fn write_from_ram(&mut self, buffer: &[u8]) -> Result<(), UarteTxError> {
+        self.write(buffer).await
+    }
+}
+
+/// A pseudo-terminal standing in for the wires of a serial port.
+///
+/// The device side is [`Pty::split`]; the terminal side is at
+/// [`Pty::path`], set to raw mode so bytes pass through unchanged.
+pub struct Pty {
+    rx: HostRx,
+    tx: HostTx,
+    terminal: File,
+    path: PathBuf,
+}
+
+impl Pty {
+    pub fn open() -> io::Result<Self> {
+        // SAFETY: The descriptor is checked, then owned by the `File`.
+        let master = unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            File::from_raw_fd(fd)
+        };
+        let fd = master.as_raw_fd();
+        // `ptsname` returns a buffer shared between calls, so opening ptys
+        // on several threads takes turns.
+        static PTSNAME: Mutex<()> = Mutex::new(());
+        let _turn = PTSNAME.lock().unwrap_or_else(|e| e.into_inner());
+        // SAFETY: `fd` is a pty master, and the name is copied out before
+        // another call can overwrite it.
+        let path = unsafe {
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let name = libc::ptsname(fd);
+            if name.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            PathBuf::from(CStr::from_ptr(name).to_str().unwrap())
+        };
+
+        // Holding the terminal side open keeps the master readable when no
+        // terminal program is attached.
+        let terminal = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&path)?;
+        make_raw(&terminal)?;
+
+        Ok(Self {
+            rx: HostRx::new(master.try_clone()?),
+            tx: HostTx::new(master),
+            terminal,
+            path,
+        })
+    }
+
+    /// Where a terminal program can open the other end.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The device side, as a receiver and a transmitter. Every split shares
+    /// the same reader and writer threads.
+    pub fn split(&self) -> (HostRx, HostTx) {
+        (self.rx.clone(), self.tx.clone())
+    }
+
+    /// The terminal side, as a terminal program would use it.
+    pub fn terminal(&self) -> io::Result<File> {
+        self.terminal.try_clone()
+    }
+}
+
+/// Runs `future` to completion, parking the thread while it waits.
+fn block_on<F: Future>(future: F) -> F::Output {
+    struct Unpark(thread::Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
+
+/// Turns off echo and line editing on a terminal.
+fn make_raw(terminal: &File) -> io::Result<()> {
+    let fd = terminal.as_raw_fd();
+    // SAFETY: `fd` is an open terminal and `termios` is filled in by
+    // `tcgetattr` before use.
+    unsafe {
+        let mut termios = std::mem::zeroed();
+        if libc::tcgetattr(fd, &mut termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut termios);
+        if libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cli::{ReplyChannel, Responder, RootCommand, Shell};
+    use crate::transport::{Transport, TransportWriter, UartTransport, UartWriter};
+
+    #[futures_test::test]
+    async fn test_pty_read_until_idle() {
+        let pty = Pty::open().unwrap();
+        let (mut rx, _) = pty.split();
+        let mut terminal = pty.terminal().unwrap();
+
+        // Nothing more is written until the line has gone quiet.
+        terminal.write_all(b"one").unwrap();
+        let mut buf = [0; 16];
+        let size = rx.read_until_idle(&mut buf).await.unwrap();
+        assert_eq!(&buf[..size], b"one");
+        terminal.write_all(b"two").unwrap();
+        let size = rx.read_until_idle(&mut buf).await.unwrap();
+        assert_eq!(&buf[..size], b"two");
+
+        terminal.write_all(b"abcdef").unwrap();
+        let size = rx.read_until_idle(&mut buf[..4]).await.unwrap();
+        assert_eq!(&buf[..size], b"abcd");
+        rx.read(&mut buf[..2]).await.unwrap();
+        assert_eq!(&buf[..2], b"ef");
+    }
+
+    #[futures_test::test]
+    async fn test_shell_over_pty() {
+        static PING: RootCommand<0> = RootCommand::new("ping", "Reply pong", []);
+        let replies: &ReplyChannel = Box::leak(Box::new(ReplyChannel::new()));
+
+        let pty = Pty::open().unwrap();
+        let (rx, tx) = pty.split();
+        let mut terminal = pty.terminal().unwrap();
+        let shell = Shell::new();
+        let mut ping = shell.register(&PING).await.unwrap();
+
+        terminal.write_all(b"ping\r").unwrap();
+
+        let mut transport = UartTransport::new(rx);
+        let line = loop {
+            if let Some(line) = transport.next_line().await.unwrap() {
+                break line;
+            }
+        };
+        shell
+            .send_with_reply(&line, Responder::new(replies))
+            .await
+            .unwrap();
+        ping.get().await.reply.reply("pong").await;
+
+        let mut writer = UartWriter::new(tx);
+        writer.write_line(&replies.receive().await).await.unwrap();
+
+        let mut echoed = [0; 6];
+        terminal.read_exact(&mut echoed).unwrap();
+        assert_eq!(&echoed, b"pong\r\n");
+    }
+}
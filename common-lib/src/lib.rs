@@ -1,21 +1,35 @@
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "host")), no_std)]
 #![allow(async_fn_in_trait)]
 
+extern crate self as common_lib;
+
+pub mod alias;
 pub mod cli;
 mod frame_ascii;
+pub mod framed;
+pub mod history;
+#[cfg(any(test, feature = "host"))]
+pub mod host;
+pub mod line_editor;
 pub mod matrix;
+#[cfg(feature = "rtt")]
+pub mod rtt;
+pub mod script;
 pub mod scroller;
+pub mod tokenizer;
 pub mod transport;
 pub mod uarte;
 
 #[cfg(test)]
 pub mod gpio_mock;
+#[cfg(test)]
+pub mod uarte_mock;
 
 pub mod prelude {
-    #[cfg(not(test))]
+    #[cfg(not(any(test, feature = "host")))]
     pub use defmt::{debug, error, info, warn};
 
-    #[cfg(test)]
+    #[cfg(any(test, feature = "host"))]
     pub use log::{debug, error, info, warn};
 }
 
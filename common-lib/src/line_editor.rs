@@ -0,0 +1,552 @@
+use core::fmt::Write;
+
+use heapless::{String, Vec};
+
+use crate::history::History;
+
+const BELL: &str = "\x07";
+const BACKSPACE: u8 = 0x08;
+const TAB: u8 = 0x09;
+const DELETE: u8 = 0x7F;
+const ESCAPE: u8 = 0x1B;
+const CTRL_A: u8 = 0x01;
+const CTRL_C: u8 = 0x03;
+const CTRL_E: u8 = 0x05;
+const CTRL_U: u8 = 0x15;
+
+pub const MAX_COMPLETIONS: usize = 16;
+
+/// Supplies candidates for tab completion.
+pub trait Completer {
+    /// Fills `candidates` with the possible endings of the last word of
+    /// `line`, returning the byte offset where that word starts.
+    async fn complete(
+        &self,
+        line: &str,
+        candidates: &mut Vec<&'static str, MAX_COMPLETIONS>,
+    ) -> usize;
+}
+
+/// Completes nothing.
+impl Completer for () {
+    async fn complete(&self, line: &str, _: &mut Vec<&'static str, MAX_COMPLETIONS>) -> usize {
+        line.len()
+    }
+}
+
+impl<T: Completer> Completer for &T {
+    async fn complete(
+        &self,
+        line: &str,
+        candidates: &mut Vec<&'static str, MAX_COMPLETIONS>,
+    ) -> usize {
+        (**self).complete(line, candidates).await
+    }
+}
+
+/// What a fed byte asks of the caller.
+#[derive(Debug, PartialEq)]
+pub enum Edit<const N: usize> {
+    /// A line was submitted.
+    Line(String<N>),
+    /// Tab was pressed; call [`LineEditor::complete`] with candidates for
+    /// [`LineEditor::before_cursor`].
+    Complete,
+}
+
+/// Room needed in the echo buffer to handle a single input byte.
+pub const fn echo_capacity(line_len: usize) -> usize {
+    line_len + 16
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum EscapeState {
+    None,
+    Escape,
+    Csi(u8),
+}
+
+/// Line discipline for a VT100-style terminal.
+///
+/// Bytes are fed in one at a time. Printable ASCII is inserted at the cursor
+/// and everything the terminal needs to show is written to the echo buffer.
+/// Backspace/delete, left/right, home/end, Ctrl-U (clear to start) and
+/// Ctrl-C (cancel) are handled, other control bytes are ignored.
+///
+/// The last `H` submitted lines are kept and recalled with up/down.
+pub struct LineEditor<const N: usize, const H: usize> {
+    line: Vec<u8, N>,
+    cursor: usize,
+    escape: EscapeState,
+    last_was_cr: bool,
+    prompt: &'static str,
+    history: History<H, N>,
+    recall: Option<usize>,
+    draft: Vec<u8, N>,
+}
+
+impl<const N: usize, const H: usize> LineEditor<N, H> {
+    pub const fn new(prompt: &'static str) -> Self {
+        Self {
+            line: Vec::new(),
+            cursor: 0,
+            escape: EscapeState::None,
+            last_was_cr: false,
+            prompt,
+            history: History::new(),
+            recall: None,
+            draft: Vec::new(),
+        }
+    }
+
+    pub fn history(&self) -> &History<H, N> {
+        &self.history
+    }
+
+    pub fn line(&self) -> &str {
+        // Only printable ASCII is ever inserted.
+        core::str::from_utf8(&self.line).unwrap_or_default()
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn before_cursor(&self) -> &str {
+        &self.line()[..self.cursor]
+    }
+
+    pub fn write_prompt<const E: usize>(&self, echo: &mut Vec<u8, E>) {
+        push_str(echo, self.prompt);
+    }
+
+    /// Reprints the prompt and line, e.g. after listing completions.
+    pub fn redraw<const E: usize>(&self, echo: &mut Vec<u8, E>) {
+        self.write_prompt(echo);
+        let _ = echo.extend_from_slice(&self.line);
+        let back = self.line.len() - self.cursor;
+        if back > 0 {
+            let _ = write!(Echo(echo), "\x1b[{}D", back);
+        }
+    }
+
+    /// Extends the word starting at `word_start` and ending at the cursor
+    /// towards `candidates`.
+    ///
+    /// Inserts the longest common completion, followed by a space when it is
+    /// unique. Returns `false` when nothing could be added and the caller
+    /// should list the candidates instead.
+    pub fn complete<const E: usize>(
+        &mut self,
+        word_start: usize,
+        candidates: &[&str],
+        echo: &mut Vec<u8, E>,
+    ) -> bool {
+        let Some((first, rest)) = candidates.split_first() else {
+            push_str(echo, BELL);
+            return true;
+        };
+
+        let common = rest.iter().fold(first.len(), |len, candidate| {
+            first
+                .bytes()
+                .zip(candidate.bytes())
+                .take(len)
+                .take_while(|(a, b)| a == b)
+                .count()
+        });
+
+        let typed = self.cursor.saturating_sub(word_start);
+        if common > typed {
+            for &byte in &first.as_bytes()[typed..common] {
+                self.insert(byte, echo);
+            }
+        } else if !rest.is_empty() {
+            return false;
+        }
+
+        if rest.is_empty() {
+            self.insert(b' ', echo);
+        }
+        true
+    }
+
+    /// Drops the line being typed and prompts for a new one, as for Ctrl-C.
+    pub fn cancel<const E: usize>(&mut self, echo: &mut Vec<u8, E>) {
+        push_str(echo, "^C\r\n");
+        self.clear();
+        self.write_prompt(echo);
+    }
+
+    /// Processes one input byte, returning the line once it is submitted.
+    pub fn feed<const E: usize>(&mut self, byte: u8, echo: &mut Vec<u8, E>) -> Option<Edit<N>> {
+        let last_was_cr = core::mem::replace(&mut self.last_was_cr, false);
+
+        match self.escape {
+            EscapeState::Escape => {
+                self.escape = match byte {
+                    b'[' | b'O' => EscapeState::Csi(0),
+                    _ => EscapeState::None,
+                };
+                return None;
+            }
+            EscapeState::Csi(param) => {
+                match byte {
+                    b'0'..=b'9' => {
+                        let param = param.saturating_mul(10).saturating_add(byte - b'0');
+                        self.escape = EscapeState::Csi(param);
+                    }
+                    0x40..=0x7E => {
+                        self.escape = EscapeState::None;
+                        self.handle_escape(byte, param, echo);
+                    }
+                    _ => self.escape = EscapeState::None,
+                }
+                return None;
+            }
+            EscapeState::None => {}
+        }
+
+        match byte {
+            b'\r' => {
+                self.last_was_cr = true;
+                return Some(Edit::Line(self.submit(echo)));
+            }
+            b'\n' if last_was_cr => {}
+            b'\n' => return Some(Edit::Line(self.submit(echo))),
+            TAB => return Some(Edit::Complete),
+            BACKSPACE | DELETE if self.cursor > 0 => {
+                self.cursor -= 1;
+                push_str(echo, "\x08");
+                self.delete_at_cursor(echo);
+            }
+            ESCAPE => self.escape = EscapeState::Escape,
+            CTRL_A => self.move_to(0, echo),
+            CTRL_E => self.move_to(self.line.len(), echo),
+            CTRL_C => self.cancel(echo),
+            CTRL_U => {
+                let removed = self.cursor;
+                self.move_to(0, echo);
+                for _ in 0..removed {
+                    self.line.remove(0);
+                }
+                self.redraw_tail(echo);
+            }
+            0x20..=0x7E => self.insert(byte, echo),
+            _ => {}
+        }
+
+        None
+    }
+
+    fn handle_escape<const E: usize>(&mut self, byte: u8, param: u8, echo: &mut Vec<u8, E>) {
+        match (byte, param) {
+            (b'A', _) => self.recall_older(echo),
+            (b'B', _) => self.recall_newer(echo),
+            (b'C', _) => self.move_to((self.cursor + 1).min(self.line.len()), echo),
+            (b'D', _) => self.move_to(self.cursor.saturating_sub(1), echo),
+            (b'H', _) | (b'~', 1) | (b'~', 7) => self.move_to(0, echo),
+            (b'F', _) | (b'~', 4) | (b'~', 8) => self.move_to(self.line.len(), echo),
+            (b'~', 3) if self.cursor < self.line.len() => self.delete_at_cursor(echo),
+            _ => {}
+        }
+    }
+
+    fn submit<const E: usize>(&mut self, echo: &mut Vec<u8, E>) -> String<N> {
+        let mut line = String::new();
+        let _ = line.push_str(self.line());
+        push_str(echo, "\r\n");
+        self.history.push(&line);
+        self.recall = None;
+        self.clear();
+        line
+    }
+
+    fn recall_older<const E: usize>(&mut self, echo: &mut Vec<u8, E>) {
+        let age = self.recall.map_or(0, |age| age + 1);
+        let Some(entry) = self.history.newest(age) else {
+            return;
+        };
+        if self.recall.is_none() {
+            self.draft = self.line.clone();
+        }
+        let mut line = Vec::new();
+        let _ = line.extend_from_slice(entry.as_bytes());
+        self.recall = Some(age);
+        self.replace_line(line, echo);
+    }
+
+    fn recall_newer<const E: usize>(&mut self, echo: &mut Vec<u8, E>) {
+        let Some(age) = self.recall else {
+            return;
+        };
+        let line = match age.checked_sub(1) {
+            Some(age) => {
+                self.recall = Some(age);
+                let mut line = Vec::new();
+                let entry = self.history.newest(age).unwrap_or_default();
+                let _ = line.extend_from_slice(entry.as_bytes());
+                line
+            }
+            None => {
+                self.recall = None;
+                core::mem::take(&mut self.draft)
+            }
+        };
+        self.replace_line(line, echo);
+    }
+
+    fn replace_line<const E: usize>(&mut self, line: Vec<u8, N>, echo: &mut Vec<u8, E>) {
+        self.move_to(0, echo);
+        self.line = line;
+        let _ = echo.extend_from_slice(&self.line);
+        push_str(echo, "\x1b[K");
+        self.cursor = self.line.len();
+    }
+
+    fn clear(&mut self) {
+        self.line.clear();
+        self.cursor = 0;
+    }
+
+    fn insert<const E: usize>(&mut self, byte: u8, echo: &mut Vec<u8, E>) {
+        if self.line.insert(self.cursor, byte).is_err() {
+            return;
+        }
+        let _ = echo.push(byte);
+        self.cursor += 1;
+        if self.cursor < self.line.len() {
+            self.redraw_tail(echo);
+        }
+    }
+
+    fn delete_at_cursor<const E: usize>(&mut self, echo: &mut Vec<u8, E>) {
+        self.line.remove(self.cursor);
+        self.redraw_tail(echo);
+    }
+
+    /// Rewrites everything after the cursor, clears the rest of the terminal
+    /// line and puts the terminal cursor back.
+    fn redraw_tail<const E: usize>(&self, echo: &mut Vec<u8, E>) {
+        let _ = echo.extend_from_slice(&self.line[self.cursor..]);
+        push_str(echo, "\x1b[K");
+        let back = self.line.len() - self.cursor;
+        if back > 0 {
+            let _ = write!(Echo(echo), "\x1b[{}D", back);
+        }
+    }
+
+    fn move_to<const E: usize>(&mut self, position: usize, echo: &mut Vec<u8, E>) {
+        if position < self.cursor {
+            let _ = write!(Echo(echo), "\x1b[{}D", self.cursor - position);
+        } else if position > self.cursor {
+            let _ = write!(Echo(echo), "\x1b[{}C", position - self.cursor);
+        }
+        self.cursor = position;
+    }
+}
+
+fn push_str<const E: usize>(echo: &mut Vec<u8, E>, s: &str) {
+    let _ = echo.extend_from_slice(s.as_bytes());
+}
+
+struct Echo<'a, const E: usize>(&'a mut Vec<u8, E>);
+
+impl<const E: usize> Write for Echo<'_, E> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.0
+            .extend_from_slice(s.as_bytes())
+            .map_err(|_| core::fmt::Error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LEN: usize = 32;
+
+    struct Terminal {
+        editor: LineEditor<LEN, 4>,
+        echo: Vec<u8, { echo_capacity(LEN) }>,
+        lines: std::vec::Vec<std::string::String>,
+        completions: usize,
+    }
+
+    impl Terminal {
+        fn new() -> Self {
+            Self {
+                editor: LineEditor::new("> "),
+                echo: Vec::new(),
+                lines: std::vec::Vec::new(),
+                completions: 0,
+            }
+        }
+
+        fn type_bytes(&mut self, bytes: &[u8]) -> std::string::String {
+            let mut echoed = std::vec::Vec::new();
+            for &byte in bytes {
+                match self.editor.feed(byte, &mut self.echo) {
+                    Some(Edit::Line(line)) => self.lines.push(line.as_str().to_owned()),
+                    Some(Edit::Complete) => self.completions += 1,
+                    None => {}
+                }
+                echoed.extend_from_slice(&self.echo);
+                self.echo.clear();
+            }
+            std::string::String::from_utf8(echoed).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_echo_and_submit() {
+        let mut terminal = Terminal::new();
+        let echoed = terminal.type_bytes(b"Hello\r");
+        assert_eq!(echoed, "Hello\r\n");
+        assert_eq!(terminal.lines, ["Hello"]);
+    }
+
+    #[test]
+    fn test_line_endings() {
+        let mut terminal = Terminal::new();
+        terminal.type_bytes(b"a\r\nb\nc\r\r");
+        assert_eq!(terminal.lines, ["a", "b", "c", ""]);
+    }
+
+    #[test]
+    fn test_backspace_and_delete() {
+        let mut terminal = Terminal::new();
+        let echoed = terminal.type_bytes(b"abc\x7f");
+        assert_eq!(echoed, "abc\x08\x1b[K");
+        terminal.type_bytes(b"\x08\x1b[D\x1b[3~\r");
+        assert_eq!(terminal.lines, [""]);
+    }
+
+    #[test]
+    fn test_cursor_movement_inserts_mid_line() {
+        let mut terminal = Terminal::new();
+        terminal.type_bytes(b"ac\x1b[D");
+        assert_eq!(terminal.editor.cursor(), 1);
+        let echoed = terminal.type_bytes(b"b");
+        assert_eq!(echoed, "bc\x1b[K\x1b[1D");
+        terminal.type_bytes(b"\x1b[F\x1bOD\x1b[H\x01\x05d\r");
+        assert_eq!(terminal.lines, ["abcd"]);
+    }
+
+    #[test]
+    fn test_cursor_clamped_to_line() {
+        let mut terminal = Terminal::new();
+        let echoed = terminal.type_bytes(b"\x1b[D\x1b[Cab\x1b[C");
+        assert_eq!(echoed, "ab");
+        assert_eq!(terminal.editor.cursor(), 2);
+    }
+
+    #[test]
+    fn test_ctrl_u_and_ctrl_c() {
+        let mut terminal = Terminal::new();
+        terminal.type_bytes(b"abc\x1b[D\x15");
+        assert_eq!(terminal.editor.line(), "c");
+        assert_eq!(terminal.editor.cursor(), 0);
+
+        let echoed = terminal.type_bytes(b"\x03");
+        assert_eq!(echoed, "^C\r\n> ");
+        assert_eq!(terminal.editor.line(), "");
+        assert!(terminal.lines.is_empty());
+    }
+
+    #[test]
+    fn test_ignores_control_bytes_and_overflow() {
+        let mut terminal = Terminal::new();
+        terminal.type_bytes(b"\x00\x07\x1b[99Xa");
+        terminal.type_bytes(&[b'x'; LEN + 4]);
+        terminal.type_bytes(b"\r");
+        assert_eq!(terminal.lines[0].len(), LEN);
+        assert!(terminal.lines[0].starts_with("ax"));
+    }
+
+    #[test]
+    fn test_history_recall() {
+        let mut terminal = Terminal::new();
+        terminal.type_bytes(b"one\rtwo\rthr");
+
+        terminal.type_bytes(b"\x1b[A");
+        assert_eq!(terminal.editor.line(), "two");
+        assert_eq!(terminal.editor.cursor(), 3);
+        terminal.type_bytes(b"\x1b[A\x1b[A");
+        assert_eq!(terminal.editor.line(), "one");
+        terminal.type_bytes(b"\x1b[B");
+        assert_eq!(terminal.editor.line(), "two");
+        terminal.type_bytes(b"\x1b[B");
+        assert_eq!(terminal.editor.line(), "thr");
+        terminal.type_bytes(b"\x1b[B");
+        assert_eq!(terminal.editor.line(), "thr");
+
+        terminal.type_bytes(b"\x1b[A\x1b[A!\r");
+        assert_eq!(terminal.lines, ["one", "two", "one!"]);
+        assert_eq!(
+            terminal
+                .editor
+                .history()
+                .iter()
+                .collect::<std::vec::Vec<_>>(),
+            ["one", "two", "one!"]
+        );
+    }
+
+    #[test]
+    fn test_history_recall_redraws_line() {
+        let mut terminal = Terminal::new();
+        terminal.type_bytes(b"ab\r");
+        let echoed = terminal.type_bytes(b"xyz\x1b[D\x1b[A");
+        assert_eq!(echoed, "xyz\x1b[1D\x1b[2Dab\x1b[K");
+    }
+
+    #[test]
+    fn test_tab_requests_completion() {
+        let mut terminal = Terminal::new();
+        terminal.type_bytes(b"scr\t");
+        assert_eq!(terminal.completions, 1);
+        assert_eq!(terminal.editor.line(), "scr");
+    }
+
+    #[test]
+    fn test_complete_unique_candidate() {
+        let mut terminal = Terminal::new();
+        terminal.type_bytes(b"scr");
+        assert!(terminal.editor.complete(0, &["scroll"], &mut terminal.echo));
+        assert_eq!(terminal.editor.line(), "scroll ");
+        assert_eq!(terminal.echo.as_slice(), b"oll ");
+    }
+
+    #[test]
+    fn test_complete_common_prefix_then_list() {
+        let mut terminal = Terminal::new();
+        terminal.type_bytes(b"scroll f");
+        assert!(terminal
+            .editor
+            .complete(7, &["forward", "forwards"], &mut terminal.echo));
+        assert_eq!(terminal.editor.line(), "scroll forward");
+
+        assert!(!terminal
+            .editor
+            .complete(7, &["forward", "forwards"], &mut terminal.echo));
+        assert_eq!(terminal.editor.line(), "scroll forward");
+    }
+
+    #[test]
+    fn test_complete_without_candidates_rings_bell() {
+        let mut terminal = Terminal::new();
+        terminal.type_bytes(b"x");
+        assert!(terminal.editor.complete(0, &[], &mut terminal.echo));
+        assert_eq!(terminal.echo.as_slice(), b"\x07");
+    }
+
+    #[test]
+    fn test_redraw_restores_cursor() {
+        let mut terminal = Terminal::new();
+        terminal.type_bytes(b"abc\x1b[D");
+        terminal.echo.clear();
+        terminal.editor.redraw(&mut terminal.echo);
+        assert_eq!(terminal.echo.as_slice(), b"> abc\x1b[1D");
+    }
+}
@@ -44,6 +44,34 @@ impl<const SIZE: usize> Default for MatrixFrame<SIZE> {
     }
 }
 
+impl<const SIZE: usize> MatrixFrame<SIZE> {
+    /// A frame from one bitmask per row, with the leftmost column in bit
+    /// `SIZE - 1`.
+    pub fn from_rows(rows: [u8; SIZE]) -> Self {
+        const { assert!(SIZE <= 8, "a row must fit in a byte") };
+        Self(core::array::from_fn(|row| {
+            core::array::from_fn(|col| match (rows[row] >> (SIZE - 1 - col)) & 1 {
+                1 => MatrixCell::Lit,
+                _ => MatrixCell::Off,
+            })
+        }))
+    }
+
+    /// Parses the rows for [`MatrixFrame::from_rows`] as two hex digits each.
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        // `from_str_radix` would also take a sign.
+        if hex.len() != 2 * SIZE || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        let mut rows = [0; SIZE];
+        for (row, digits) in rows.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            let digits = core::str::from_utf8(digits).ok()?;
+            *row = u8::from_str_radix(digits, 16).ok()?;
+        }
+        Some(Self::from_rows(rows))
+    }
+}
+
 pub struct LedMatrix<P, const SIZE: usize>
 where
     P: MatrixPin,
@@ -96,6 +124,19 @@ mod tests {
     use MatrixCell::Lit;
     use MatrixCell::Off;
 
+    #[test]
+    fn test_frame_from_hex() {
+        let frame = MatrixFrame::<3>::from_hex("040201").unwrap();
+        assert_eq!(
+            frame,
+            MatrixFrame([[Lit, Off, Off], [Off, Lit, Off], [Off, Off, Lit]])
+        );
+        assert_eq!(MatrixFrame::<3>::from_hex("0402"), None);
+        assert_eq!(MatrixFrame::<3>::from_hex("04020g"), None);
+        assert_eq!(MatrixFrame::<3>::from_hex("0402é"), None);
+        assert_eq!(MatrixFrame::<3>::from_hex("04+20f"), None);
+    }
+
     #[futures_test::test]
     async fn test_display_frame_1x1_all_lit() {
         let mut col = [MockOutput::new()];
@@ -0,0 +1,88 @@
+//! The debug probe as a serial line, for running the shell from
+//! `probe-rs attach` without a USB-serial adapter.
+//!
+//! [`RttRx`] and [`RttTx`] stand in for the UARTE, so any [`Transport`]
+//! can read from an RTT down channel and write to an up channel. Neither
+//! may be the channel defmt logs to.
+//!
+//! [`Transport`]: crate::transport::Transport
+
+use embassy_time::{Duration, Instant, Timer};
+use rtt_target::{ChannelMode, DownChannel, UpChannel};
+
+use crate::uarte::{UarteRx, UarteRxError, UarteTx, UarteTxError};
+
+/// How often RTT channels are checked, as the probe gives no interrupts.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long a write waits for the host to make room before giving up, so
+/// that a detached probe can't stall the writer.
+pub const WRITE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Input from an RTT down channel, read as from a UARTE.
+///
+/// The line counts as idle as soon as there is nothing left to read.
+pub struct RttRx {
+    channel: DownChannel,
+}
+
+impl RttRx {
+    pub fn new(channel: DownChannel) -> Self {
+        Self { channel }
+    }
+}
+
+impl UarteRx for RttRx {
+    async fn read(&mut self, mut buffer: &mut [u8]) -> Result<(), UarteRxError> {
+        while !buffer.is_empty() {
+            let size = self.read_until_idle(buffer).await?;
+            buffer = &mut buffer[size..];
+        }
+        Ok(())
+    }
+
+    async fn read_until_idle(&mut self, buffer: &mut [u8]) -> Result<usize, UarteRxError> {
+        loop {
+            let size = self.channel.read(buffer);
+            if size > 0 {
+                return Ok(size);
+            }
+            Timer::after(POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// Output to an RTT up channel, written as to a UARTE.
+pub struct RttTx {
+    channel: UpChannel,
+}
+
+impl RttTx {
+    pub fn new(mut channel: UpChannel) -> Self {
+        channel.set_mode(ChannelMode::NoBlockTrim);
+        Self { channel }
+    }
+}
+
+impl UarteTx for RttTx {
+    async fn write(&mut self, mut buffer: &[u8]) -> Result<(), UarteTxError> {
+        let mut deadline = Instant::now() + WRITE_TIMEOUT;
+        loop {
+            let size = self.channel.write(buffer);
+            buffer = &buffer[size..];
+            if buffer.is_empty() {
+                return Ok(());
+            }
+            if size > 0 {
+                deadline = Instant::now() + WRITE_TIMEOUT;
+            } else if Instant::now() >= deadline {
+                return Err(UarteTxError::Timeout);
+            }
+            Timer::after(POLL_INTERVAL).await;
+        }
+    }
+
+    async fn write_from_ram(&mut self, buffer: &[u8]) -> Result<(), UarteTxError> {
+        self.write(buffer).await
+    }
+}
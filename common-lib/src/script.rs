@@ -0,0 +1,436 @@
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_time::{Duration, Timer};
+use heapless::{String, Vec};
+use thiserror::Error;
+
+use crate::cli::{Arg, ArgValue, CommandHandlers, RawShell, Responder, ShellError};
+use crate::tokenizer::split_unquoted;
+
+/// How deeply `repeat` blocks may nest.
+pub const MAX_NESTING: usize = 4;
+/// The longest name of a script in a [`ScriptStore`].
+pub const MAX_SCRIPT_NAME: usize = 16;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ScriptError {
+    #[error("script too long")]
+    TooLong,
+    #[error("too many statements")]
+    TooManyOps,
+    #[error("unbalanced braces")]
+    UnbalancedBraces,
+    #[error("repeat nested too deeply")]
+    TooDeep,
+    #[error("expected repeat [count] {{ .. }}")]
+    InvalidRepeat,
+    #[error("invalid sleep duration")]
+    InvalidSleep,
+    #[error("script store is full")]
+    StoreFull,
+    #[error("script name too long")]
+    NameTooLong,
+    #[error("unknown script")]
+    Unknown,
+    #[error("{0}")]
+    Command(#[from] ShellError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    /// A command line, as a byte range of the source.
+    Command {
+        start: usize,
+        end: usize,
+    },
+    Sleep(Duration),
+    /// Runs the ops up to the matching [`Op::End`] `count` times, or forever.
+    /// `end` is the index just past that `End`.
+    Repeat {
+        count: Option<u32>,
+        end: usize,
+    },
+    End,
+}
+
+/// A compiled script of up to `L` bytes and `N` statements.
+///
+/// Statements are separated by `;` or newlines. Besides commands, which run
+/// through normal shell dispatch, a script may use:
+/// - `sleep <ms>`, taking a duration as in `500`, `500ms` or `2s`
+/// - `repeat <n> { .. }` to run a block `n` times, or `repeat { .. }` to
+///   run it until the script is dropped
+///
+/// ```text
+/// Hello; repeat 3 { scroll forward HI; sleep 2s }
+/// ```
+#[derive(Clone)]
+pub struct Script<const L: usize, const N: usize> {
+    source: String<L>,
+    ops: Vec<Op, N>,
+}
+
+impl<const L: usize, const N: usize> Script<L, N> {
+    pub fn parse(text: &str) -> Result<Self, ScriptError> {
+        let mut script = Self {
+            source: String::try_from(text).map_err(|_| ScriptError::TooLong)?,
+            ops: Vec::new(),
+        };
+        // Indices of the `Repeat` ops whose blocks are still open.
+        let mut open: Vec<usize, MAX_NESTING> = Vec::new();
+
+        // The end of the text closes the last statement like a newline.
+        for piece in split_unquoted(text, |c| matches!(c, ';' | '\n' | '{' | '}')) {
+            let statement = piece.text.trim();
+            let offset = piece.start + piece.text.len() - piece.text.trim_start().len();
+            let separator = piece.separator.unwrap_or('\n');
+            if separator == '{' {
+                let count = repeat_count(statement)?;
+                let index = script.ops.len();
+                open.push(index).map_err(|_| ScriptError::TooDeep)?;
+                script.push(Op::Repeat { count, end: 0 })?;
+            } else {
+                script.statement(statement, offset)?;
+            }
+            if separator == '}' {
+                let repeat = open.pop().ok_or(ScriptError::UnbalancedBraces)?;
+                script.push(Op::End)?;
+                let end = script.ops.len();
+                if let Op::Repeat { end: e, .. } = &mut script.ops[repeat] {
+                    *e = end;
+                }
+            }
+        }
+
+        if !open.is_empty() {
+            return Err(ScriptError::UnbalancedBraces);
+        }
+        Ok(script)
+    }
+
+    /// Runs the script, sending each command to `shell` with `reply`.
+    ///
+    /// Stops at the first command that fails to dispatch. Each pass through
+    /// a `repeat` block yields, so a loop without a `sleep` cannot starve
+    /// other tasks.
+    pub async fn run<
+        M: RawMutex + 'static,
+        const LINE: usize,
+        const DEPTH: usize,
+        const REGISTRY: usize,
+        H: CommandHandlers<LINE>,
+    >(
+        &self,
+        shell: &RawShell<M, LINE, DEPTH, REGISTRY, H>,
+        reply: Responder,
+    ) -> Result<(), ScriptError> {
+        // The first op of each running block, and the passes left after this
+        // one.
+        let mut loops: Vec<(usize, Option<u32>), MAX_NESTING> = Vec::new();
+        let mut next = 0;
+
+        while let Some(&op) = self.ops.get(next) {
+            next += 1;
+            match op {
+                Op::Command { start, end } => {
+                    shell
+                        .send_with_reply(&self.source[start..end], reply)
+                        .await?
+                }
+                Op::Sleep(duration) => Timer::after(duration).await,
+                Op::Repeat {
+                    count: Some(0),
+                    end,
+                } => next = end,
+                Op::Repeat { count, .. } => {
+                    // Parsing bounds the nesting, so this cannot overflow.
+                    let _ = loops.push((next, count.map(|n| n - 1)));
+                }
+                Op::End => {
+                    let Some((start, remaining)) = loops.last_mut() else {
+                        continue;
+                    };
+                    match remaining {
+                        Some(0) => {
+                            loops.pop();
+                        }
+                        Some(n) => {
+                            *n -= 1;
+                            next = *start;
+                        }
+                        None => next = *start,
+                    }
+                    embassy_futures::yield_now().await;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, statement: &str, offset: usize) -> Result<(), ScriptError> {
+        let (word, rest) = statement
+            .split_once(|c: char| c.is_ascii_whitespace())
+            .unwrap_or((statement, ""));
+        match word {
+            "" => Ok(()),
+            "sleep" => match Arg::duration("ms").parse(rest.trim()) {
+                Ok(ArgValue::Duration(duration)) => self.push(Op::Sleep(duration)),
+                _ => Err(ScriptError::InvalidSleep),
+            },
+            "repeat" => Err(ScriptError::InvalidRepeat),
+            _ => self.push(Op::Command {
+                start: offset,
+                end: offset + statement.len(),
+            }),
+        }
+    }
+
+    fn push(&mut self, op: Op) -> Result<(), ScriptError> {
+        self.ops.push(op).map_err(|_| ScriptError::TooManyOps)
+    }
+}
+
+/// Up to `N` named scripts, compiled as they are defined.
+pub struct ScriptStore<const N: usize, const L: usize, const OPS: usize> {
+    scripts: Vec<(String<MAX_SCRIPT_NAME>, Script<L, OPS>), N>,
+}
+
+impl<const N: usize, const L: usize, const OPS: usize> ScriptStore<N, L, OPS> {
+    pub const fn new() -> Self {
+        Self {
+            scripts: Vec::new(),
+        }
+    }
+
+    /// Compiles `text` and stores it as `name`, replacing any script of that
+    /// name. A script that fails to compile leaves the store unchanged.
+    pub fn define(&mut self, name: &str, text: &str) -> Result<(), ScriptError> {
+        let name = String::try_from(name).map_err(|_| ScriptError::NameTooLong)?;
+        let script = Script::parse(text)?;
+        match self.scripts.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, old)) => *old = script,
+            None => self
+                .scripts
+                .push((name, script))
+                .map_err(|_| ScriptError::StoreFull)?,
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Script<L, OPS>> {
+        self.scripts
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, script)| script)
+    }
+
+    /// The names of the stored scripts, in the order they were defined.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.scripts.iter().map(|(name, _)| name.as_str())
+    }
+}
+
+impl<const N: usize, const L: usize, const OPS: usize> Default for ScriptStore<N, L, OPS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The count of a `repeat [n]` statement opening a block, `None` for
+/// forever.
+fn repeat_count(statement: &str) -> Result<Option<u32>, ScriptError> {
+    let mut words = statement.split_ascii_whitespace();
+    if words.next() != Some("repeat") {
+        return Err(ScriptError::InvalidRepeat);
+    }
+    let count = match words.next() {
+        Some(n) => Some(n.parse().map_err(|_| ScriptError::InvalidRepeat)?),
+        None => None,
+    };
+    if words.next().is_some() {
+        return Err(ScriptError::InvalidRepeat);
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod test {
+    use embassy_time::{Instant, WithTimeout};
+
+    use super::*;
+    use crate::cli::{ArgKind, ParsedCommand, RootCommand, Shell, SubCommand};
+
+    type TestScript = Script<128, 16>;
+
+    const LAMP: [SubCommand; 2] = [
+        SubCommand {
+            command: "on",
+            description: "",
+            args: &[],
+            children: &[],
+        },
+        SubCommand {
+            command: "set",
+            description: "",
+            args: &[Arg {
+                name: "level",
+                kind: ArgKind::Int { min: 0, max: 9 },
+            }],
+            children: &[],
+        },
+    ];
+
+    fn render(command: &ParsedCommand) -> std::string::String {
+        let mut out = command.path.join(" ");
+        for arg in command.args() {
+            if let ArgValue::Int(value) = arg {
+                out += &format!(" {value}");
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_script_parse() {
+        let script = TestScript::parse(
+            "lamp on; repeat 2 {\n  lamp set 1; sleep 1s\n}\nrepeat{'}'}; sleep 20ms;",
+        )
+        .unwrap();
+        assert_eq!(
+            script.ops.as_slice(),
+            [
+                Op::Command { start: 0, end: 7 },
+                Op::Repeat {
+                    count: Some(2),
+                    end: 5
+                },
+                Op::Command { start: 22, end: 32 },
+                Op::Sleep(Duration::from_secs(1)),
+                Op::End,
+                Op::Repeat {
+                    count: None,
+                    end: 8
+                },
+                Op::Command { start: 52, end: 55 },
+                Op::End,
+                Op::Sleep(Duration::from_millis(20)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_script_parse_errors() {
+        let parse = |text| TestScript::parse(text).err();
+        assert_eq!(
+            parse("repeat 2 { lamp on"),
+            Some(ScriptError::UnbalancedBraces)
+        );
+        assert_eq!(parse("lamp on }"), Some(ScriptError::UnbalancedBraces));
+        assert_eq!(parse("lamp { on }"), Some(ScriptError::InvalidRepeat));
+        assert_eq!(parse("repeat two { on }"), Some(ScriptError::InvalidRepeat));
+        assert_eq!(parse("repeat 2; lamp on"), Some(ScriptError::InvalidRepeat));
+        assert_eq!(parse("sleep soon"), Some(ScriptError::InvalidSleep));
+        assert_eq!(parse("sleep"), Some(ScriptError::InvalidSleep));
+        assert_eq!(
+            parse("repeat { repeat { repeat { repeat { repeat { } } } } }"),
+            Some(ScriptError::TooDeep)
+        );
+        assert_eq!(
+            Script::<128, 2>::parse("a; b; c").err(),
+            Some(ScriptError::TooManyOps)
+        );
+        assert_eq!(
+            Script::<4, 2>::parse("lamp on").err(),
+            Some(ScriptError::TooLong)
+        );
+    }
+
+    #[test]
+    fn test_script_store() {
+        let mut store = ScriptStore::<2, 32, 4>::new();
+        store.define("blink", "lamp on; sleep 1s").unwrap();
+        store.define("dim", "lamp set 1").unwrap();
+        assert_eq!(store.get("blink").unwrap().ops.len(), 2);
+
+        // Redefining replaces in place, and a bad script keeps the old one.
+        store.define("blink", "lamp on").unwrap();
+        assert_eq!(store.get("blink").unwrap().ops.len(), 1);
+        assert_eq!(
+            store.define("blink", "repeat {"),
+            Err(ScriptError::UnbalancedBraces)
+        );
+        assert_eq!(store.get("blink").unwrap().ops.len(), 1);
+
+        assert_eq!(store.define("more", "lamp on"), Err(ScriptError::StoreFull));
+        assert_eq!(
+            store.define("a_very_long_script_name", "lamp on"),
+            Err(ScriptError::NameTooLong)
+        );
+        assert!(store.get("more").is_none());
+        assert_eq!(
+            store.names().collect::<std::vec::Vec<_>>(),
+            ["blink", "dim"]
+        );
+    }
+
+    #[futures_test::test]
+    async fn test_script_run() {
+        static ROOT: RootCommand<2> = RootCommand::new("lamp", "", LAMP);
+        let shell = Shell::new();
+        let mut receiver = shell.register(&ROOT).await.unwrap();
+
+        let script =
+            TestScript::parse("lamp on; repeat 2 { lamp set 1; sleep 10ms }; repeat 0 { bogus }")
+                .unwrap();
+        let started = Instant::now();
+        script.run(&shell, Responder::detached()).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(20));
+
+        let mut seen = std::vec::Vec::new();
+        while let Ok(request) = receiver.get().with_timeout(Duration::from_millis(10)).await {
+            seen.push(render(&request.command));
+        }
+        assert_eq!(seen, ["on", "set 1", "set 1"]);
+    }
+
+    #[futures_test::test]
+    async fn test_script_stops_on_error() {
+        static ROOT: RootCommand<2> = RootCommand::new("lamp", "", LAMP);
+        let shell = Shell::new();
+        let mut receiver = shell.register(&ROOT).await.unwrap();
+
+        let script = TestScript::parse("lamp on; lamp set 10; lamp on").unwrap();
+        assert_eq!(
+            script.run(&shell, Responder::detached()).await,
+            Err(ScriptError::Command(ShellError::InvalidArg("level")))
+        );
+
+        let request = receiver.get().await;
+        assert_eq!(render(&request.command), "on");
+        assert!(receiver
+            .get()
+            .with_timeout(Duration::from_millis(10))
+            .await
+            .is_err());
+    }
+
+    #[futures_test::test]
+    async fn test_script_repeat_forever_yields() {
+        static ROOT: RootCommand<2> = RootCommand::new("lamp", "", LAMP);
+        let shell = Shell::new();
+        let mut receiver = shell.register(&ROOT).await.unwrap();
+
+        // The handler keeps up with an endless loop and the script only
+        // ends when dropped.
+        let script = TestScript::parse("repeat { lamp on }").unwrap();
+        let handler = async {
+            for _ in 0..20 {
+                receiver.get().await;
+            }
+        };
+        let run = script.run(&shell, Responder::detached());
+        match embassy_futures::select::select(run, handler).await {
+            embassy_futures::select::Either::First(result) => panic!("script ended: {result:?}"),
+            embassy_futures::select::Either::Second(()) => {}
+        }
+    }
+}
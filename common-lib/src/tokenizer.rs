@@ -0,0 +1,273 @@
+use core::str::Chars;
+
+use heapless::{String, Vec};
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum TokenizeError {
+    #[error("token too long")]
+    TokenTooLong,
+    #[error("too many tokens")]
+    TooManyTokens,
+    #[error("unterminated quote")]
+    UnterminatedQuote,
+    #[error("dangling escape")]
+    DanglingEscape,
+}
+
+/// The tokens of a line, stored back to back.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Tokens<const LEN: usize, const N: usize> {
+    text: String<LEN>,
+    /// Where each token ends in `text`.
+    ends: Vec<usize, N>,
+}
+
+impl<const LEN: usize, const N: usize> Tokens<LEN, N> {
+    pub fn len(&self) -> usize {
+        self.ends.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ends.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&str> {
+        let end = *self.ends.get(index)?;
+        let start = index.checked_sub(1).map_or(0, |i| self.ends[i]);
+        Some(&self.text[start..end])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        (0..self.len()).filter_map(|index| self.get(index))
+    }
+
+    /// Appends `token` as it is, without unquoting.
+    pub fn push_token(&mut self, token: &str) -> Result<(), TokenizeError> {
+        self.text
+            .push_str(token)
+            .map_err(|_| TokenizeError::TokenTooLong)?;
+        self.end()
+    }
+
+    fn push(&mut self, c: char) -> Result<(), TokenizeError> {
+        self.text.push(c).map_err(|_| TokenizeError::TokenTooLong)
+    }
+
+    fn end(&mut self) -> Result<(), TokenizeError> {
+        self.ends
+            .push(self.text.len())
+            .map_err(|_| TokenizeError::TooManyTokens)
+    }
+}
+
+/// Splits a line into at most `N` tokens, holding at most `LEN` bytes of
+/// token text between them.
+///
+/// Runs of whitespace separate tokens. Double quotes group words into one
+/// token and allow backslash escapes inside, single quotes group words
+/// literally, and a backslash outside quotes escapes the next character.
+/// `\n` and `\t` produce a newline and a tab.
+///
+/// Quotes and escapes only ever shorten the text, so a `LEN` as long as
+/// the line always fits.
+pub fn tokenize<const LEN: usize, const N: usize>(
+    line: &str,
+) -> Result<Tokens<LEN, N>, TokenizeError> {
+    let mut tokens = Tokens {
+        text: String::new(),
+        ends: Vec::new(),
+    };
+    let mut in_token = false;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        if c.is_ascii_whitespace() {
+            if in_token {
+                tokens.end()?;
+                in_token = false;
+            }
+            continue;
+        }
+        in_token = true;
+        match c {
+            '"' => loop {
+                match chars.next() {
+                    None => return Err(TokenizeError::UnterminatedQuote),
+                    Some('"') => break,
+                    Some('\\') => tokens.push(escaped(&mut chars)?)?,
+                    Some(c) => tokens.push(c)?,
+                }
+            },
+            '\'' => loop {
+                match chars.next() {
+                    None => return Err(TokenizeError::UnterminatedQuote),
+                    Some('\'') => break,
+                    Some(c) => tokens.push(c)?,
+                }
+            },
+            '\\' => tokens.push(escaped(&mut chars)?)?,
+            c => tokens.push(c)?,
+        }
+    }
+
+    if in_token {
+        tokens.end()?;
+    }
+
+    Ok(tokens)
+}
+
+/// A piece of a line cut by [`split_unquoted`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Piece<'a> {
+    /// Where the piece starts in the line.
+    pub start: usize,
+    pub text: &'a str,
+    /// The separator that ended the piece, or `None` for the last one.
+    pub separator: Option<char>,
+}
+
+/// Splits a line at every character `is_separator` accepts, except where
+/// [`tokenize`] would read it as quoted or escaped.
+pub fn split_unquoted<F: Fn(char) -> bool>(line: &str, is_separator: F) -> SplitUnquoted<'_, F> {
+    SplitUnquoted {
+        line,
+        start: Some(0),
+        is_separator,
+    }
+}
+
+pub struct SplitUnquoted<'a, F> {
+    line: &'a str,
+    /// Where the next piece starts, until the line is used up.
+    start: Option<usize>,
+    is_separator: F,
+}
+
+impl<'a, F: Fn(char) -> bool> Iterator for SplitUnquoted<'a, F> {
+    type Item = Piece<'a>;
+
+    fn next(&mut self) -> Option<Piece<'a>> {
+        let start = self.start?;
+        let rest = &self.line[start..];
+        let mut quote = None;
+        let mut escaped = false;
+        for (i, c) in rest.char_indices() {
+            match (quote, c) {
+                _ if escaped => escaped = false,
+                (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+                (Some('\''), _) => {}
+                (_, '\\') => escaped = true,
+                (Some(_), _) => {}
+                (None, '"' | '\'') => quote = Some(c),
+                (None, c) if (self.is_separator)(c) => {
+                    self.start = Some(start + i + c.len_utf8());
+                    return Some(Piece {
+                        start,
+                        text: &rest[..i],
+                        separator: Some(c),
+                    });
+                }
+                _ => {}
+            }
+        }
+        self.start = None;
+        Some(Piece {
+            start,
+            text: rest,
+            separator: None,
+        })
+    }
+}
+
+fn escaped(chars: &mut Chars) -> Result<char, TokenizeError> {
+    match chars.next() {
+        None => Err(TokenizeError::DanglingEscape),
+        Some('n') => Ok('\n'),
+        Some('t') => Ok('\t'),
+        Some(c) => Ok(c),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tokens(line: &str) -> Result<std::vec::Vec<std::string::String>, TokenizeError> {
+        let tokens: Tokens<32, 4> = tokenize(line)?;
+        Ok(tokens.iter().map(|t| t.to_owned()).collect())
+    }
+
+    #[test]
+    fn test_tokenize_collapses_whitespace() {
+        assert_eq!(
+            tokens("  scroll   forward\tHI ").unwrap(),
+            ["scroll", "forward", "HI"]
+        );
+        assert!(tokens("").unwrap().is_empty());
+        assert!(tokens("   ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_tokenize_quotes() {
+        assert_eq!(
+            tokens(r#"scroll forward "HELLO WORLD""#).unwrap(),
+            ["scroll", "forward", "HELLO WORLD"]
+        );
+        assert_eq!(tokens("say 'a \"b\" c'").unwrap(), ["say", "a \"b\" c"]);
+        assert_eq!(tokens(r#"a"b c"d"#).unwrap(), ["ab cd"]);
+        assert_eq!(tokens(r#"say "" ''"#).unwrap(), ["say", "", ""]);
+    }
+
+    #[test]
+    fn test_tokenize_escapes() {
+        assert_eq!(tokens(r"HELLO\ WORLD").unwrap(), ["HELLO WORLD"]);
+        assert_eq!(tokens(r#""say \"hi\"""#).unwrap(), [r#"say "hi""#]);
+        assert_eq!(tokens(r"a\\b \n").unwrap(), [r"a\b", "\n"]);
+        assert_eq!(tokens(r"'\n'").unwrap(), [r"\n"]);
+    }
+
+    #[test]
+    fn test_split_unquoted() {
+        let pieces: std::vec::Vec<_> =
+            split_unquoted("a 'b c'\n{d}", |c| c == ' ' || c == '\n' || c == '}').collect();
+        assert_eq!(
+            pieces,
+            [
+                Piece {
+                    start: 0,
+                    text: "a",
+                    separator: Some(' '),
+                },
+                Piece {
+                    start: 2,
+                    text: "'b c'",
+                    separator: Some('\n'),
+                },
+                Piece {
+                    start: 8,
+                    text: "{d",
+                    separator: Some('}'),
+                },
+                Piece {
+                    start: 11,
+                    text: "",
+                    separator: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_errors() {
+        assert_eq!(tokens("a b c d e"), Err(TokenizeError::TooManyTokens));
+        assert_eq!(tokens(&"A".repeat(33)), Err(TokenizeError::TokenTooLong));
+        // The limit is on the text of all the tokens together.
+        let two = format!("{} {}", "A".repeat(16), "B".repeat(17));
+        assert_eq!(tokens(&two), Err(TokenizeError::TokenTooLong));
+        assert_eq!(tokens(r#"say "hi"#), Err(TokenizeError::UnterminatedQuote));
+        assert_eq!(tokens("say 'hi"), Err(TokenizeError::UnterminatedQuote));
+        assert_eq!(tokens(r"say hi\"), Err(TokenizeError::DanglingEscape));
+    }
+}
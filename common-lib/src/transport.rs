@@ -1,61 +1,453 @@
+use crate::line_editor::{echo_capacity, Completer, Edit, LineEditor, MAX_COMPLETIONS};
 use crate::prelude::*;
-use crate::uarte::{UarteRx, UarteRxError};
+use crate::uarte::{UarteRx, UarteRxError, UarteTx, UarteTxError};
+use core::fmt::{self, Write};
 use core::str;
-use heapless::String;
+use heapless::{String, Vec};
+use thiserror::Error;
+
+/// Longest line a transport reads, matching what a framed request carries.
+pub use shell_protocol::MAX_LINE_LEN;
 
 pub trait Transport {
     type Error;
     async fn next_line(&mut self) -> Result<Option<String<MAX_LINE_LEN>>, Self::Error>;
 }
 
-pub static MAX_LINE_LEN: usize = 256;
+/// The output side of a [`Transport`], for writing back to the user.
+pub trait TransportWriter {
+    type Error;
+    async fn write(&mut self, bytes: &[u8]) -> Result<(), Self::Error>;
+
+    /// Writes `line` followed by `\r\n`.
+    async fn write_line(&mut self, line: &str) -> Result<(), Self::Error> {
+        self.write(line.as_bytes()).await?;
+        self.write(b"\r\n").await
+    }
+
+    /// Writes formatted text, turning each `\n` into `\r\n`, so that
+    /// `writeln!(console, "frame rate {}", fps).await` works as expected.
+    ///
+    /// The text is formatted once into a buffer, cut off after
+    /// [`MAX_FMT_LEN`] bytes, and written from there.
+    async fn write_fmt(&mut self, args: fmt::Arguments<'_>) -> Result<(), Self::Error> {
+        let mut out = CrLf::default();
+        // Formatting stops with an error once the buffer is full.
+        let _ = fmt::write(&mut out, args);
+        self.write(&out.buf).await
+    }
+}
+
+/// Longest single write to the transmitter. Data in flash is copied to RAM
+/// for the UARTE, so this bounds the copy.
+pub const WRITE_CHUNK_LEN: usize = 64;
+/// Longest output of one [`TransportWriter::write_fmt`].
+pub const MAX_FMT_LEN: usize = 256;
+
+/// Collects formatted output with `\r\n` line endings, until its buffer is
+/// full.
+#[derive(Default)]
+struct CrLf {
+    last: u8,
+    buf: Vec<u8, MAX_FMT_LEN>,
+}
+
+impl Write for CrLf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if byte == b'\n' && self.last != b'\r' {
+                self.buf.push(b'\r').map_err(|_| fmt::Error)?;
+            }
+            self.buf.push(byte).map_err(|_| fmt::Error)?;
+            self.last = byte;
+        }
+        Ok(())
+    }
+}
+
+/// Which characters end a line for [`UartTransport`].
+///
+/// Whatever the choice, `\r\n` ends a single line.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LineEnding {
+    /// `\r`, as sent by terminals.
+    Cr,
+    /// `\n`, as sent by most scripts.
+    Lf,
+    /// Either of them.
+    #[default]
+    Any,
+}
+
+impl LineEnding {
+    fn ends_line(self, byte: u8) -> bool {
+        match self {
+            LineEnding::Cr => byte == b'\r',
+            LineEnding::Lf => byte == b'\n',
+            LineEnding::Any => byte == b'\r' || byte == b'\n',
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum UartTransportError {
+    #[error("receive failed")]
+    Rx(#[from] UarteRxError),
+    #[error("line is not valid UTF-8")]
+    InvalidUtf8,
+    #[error("line too long")]
+    LineOverflow,
+}
 
+/// A [`Transport`] for lines sent as a whole, as by a script.
+///
+/// A line that is too long or not UTF-8 is reported as an error, and input
+/// carries on with the next line.
 pub struct UartTransport<T: UarteRx> {
-    buf: String<256>,
+    line: Vec<u8, MAX_LINE_LEN>,
+    input: [u8; 64],
+    start: usize,
+    end: usize,
+    line_ending: LineEnding,
+    /// Whether the last line ended in `\r`, so a `\n` to follow is part of
+    /// its line ending.
+    after_cr: bool,
+    /// Whether the current line overflowed and is being skipped.
+    discarding: bool,
     rx: T,
 }
 
 impl<T: UarteRx> UartTransport<T> {
     pub fn new(uarte_rx: T) -> Self {
         Self {
-            buf: String::new(),
+            line: Vec::new(),
+            input: [0; 64],
+            start: 0,
+            end: 0,
+            line_ending: LineEnding::default(),
+            after_cr: false,
+            discarding: false,
             rx: uarte_rx,
         }
     }
+
+    pub fn with_line_ending(mut self, line_ending: LineEnding) -> Self {
+        self.line_ending = line_ending;
+        self
+    }
+
+    /// Takes the complete lines already received, without reading more.
+    pub fn drain_lines(
+        &mut self,
+    ) -> impl Iterator<Item = Result<String<MAX_LINE_LEN>, UartTransportError>> + '_ {
+        core::iter::from_fn(|| self.take_line())
+    }
+
+    fn take_line(&mut self) -> Option<Result<String<MAX_LINE_LEN>, UartTransportError>> {
+        while self.start < self.end {
+            let byte = self.input[self.start];
+            self.start += 1;
+
+            if core::mem::take(&mut self.after_cr) && byte == b'\n' {
+                continue;
+            }
+
+            if self.line_ending.ends_line(byte) {
+                self.after_cr = byte == b'\r';
+                let mut line = core::mem::take(&mut self.line);
+                if core::mem::take(&mut self.discarding) {
+                    continue;
+                }
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                return Some(String::from_utf8(line).map_err(|_| UartTransportError::InvalidUtf8));
+            }
+
+            if self.discarding {
+                continue;
+            }
+            if self.line.push(byte).is_err() {
+                self.line.clear();
+                self.discarding = true;
+                return Some(Err(UartTransportError::LineOverflow));
+            }
+        }
+        None
+    }
 }
 
 impl<T: UarteRx> Transport for UartTransport<T> {
-    type Error = UarteRxError;
+    type Error = UartTransportError;
+    async fn next_line(&mut self) -> Result<Option<String<MAX_LINE_LEN>>, Self::Error> {
+        // Keep what's left of the last read, and read more after it.
+        self.input.copy_within(self.start..self.end, 0);
+        self.end -= self.start;
+        self.start = 0;
+
+        if self.end < self.input.len() {
+            match read_recovering(&mut self.rx, &mut self.input[self.end..]).await? {
+                Some(size) => self.end += size,
+                None => self.line.clear(),
+            }
+        }
+
+        if let Ok(line) = str::from_utf8(&self.line) {
+            info!("{:?}", line);
+        }
+
+        self.take_line().transpose()
+    }
+}
+
+/// A [`TransportWriter`] on a transmitter, writing at most
+/// [`WRITE_CHUNK_LEN`] bytes at a time.
+pub struct UartWriter<W: UarteTx> {
+    tx: W,
+}
+
+impl<W: UarteTx> UartWriter<W> {
+    pub fn new(uarte_tx: W) -> Self {
+        Self { tx: uarte_tx }
+    }
+}
+
+impl<W: UarteTx> TransportWriter for UartWriter<W> {
+    type Error = UarteTxError;
+    async fn write(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        for chunk in bytes.chunks(WRITE_CHUNK_LEN) {
+            self.tx.write(chunk).await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ConsoleError {
+    #[error("receive failed")]
+    Rx(#[from] UarteRxError),
+    #[error("transmit failed")]
+    Tx(#[from] UarteTxError),
+}
+
+const ECHO_BUF_LEN: usize = 2 * echo_capacity(MAX_LINE_LEN);
+
+/// Line answered by the console itself with the recalled lines.
+///
+/// History belongs to a console rather than the shell, so this is not a
+/// shell command: it is not listed by `help`, can't be aliased or used in a
+/// `;` sequence, and only exists on transports with a line editor. The `!`
+/// keeps it apart from the shell's command names.
+pub const HISTORY_COMMAND: &str = "!history";
+
+/// A [`Transport`] for a human at a terminal: input is run through a
+/// [`LineEditor`] and echoed back through the transmitter.
+///
+/// The last `H` lines can be recalled with up/down, and listed by entering
+/// the console-local [`HISTORY_COMMAND`]. Tab completes using the
+/// [`Completer`].
+pub struct InteractiveTransport<R: UarteRx, W: UarteTx, C: Completer, const H: usize> {
+    console: Console<W, C, H>,
+    input: [u8; 64],
+    start: usize,
+    end: usize,
+    rx: R,
+}
+
+impl<R: UarteRx, W: UarteTx, C: Completer, const H: usize> InteractiveTransport<R, W, C, H> {
+    pub fn new(uarte_rx: R, uarte_tx: W, completer: C) -> Self {
+        Self {
+            console: Console::new(uarte_tx, completer),
+            input: [0; 64],
+            start: 0,
+            end: 0,
+            rx: uarte_rx,
+        }
+    }
+}
+
+impl<R: UarteRx, W: UarteTx, C: Completer, const H: usize> Transport
+    for InteractiveTransport<R, W, C, H>
+{
+    type Error = ConsoleError;
     async fn next_line(&mut self) -> Result<Option<String<MAX_LINE_LEN>>, Self::Error> {
-        let mut input_buffer = [0; 64];
+        let mut echo = Vec::new();
+        self.console.prompt(&mut echo);
 
-        let size = self.rx.read_until_idle(&mut input_buffer).await?;
+        if self.start == self.end {
+            self.console.flush(&mut echo).await?;
+            self.start = 0;
+            self.end = match read_recovering(&mut self.rx, &mut self.input).await? {
+                Some(size) => size,
+                None => {
+                    self.console.cancel(&mut echo);
+                    0
+                }
+            };
+        }
 
-        let input_string = str::from_utf8(&input_buffer[0..size]).unwrap();
+        let mut line = None;
+        while self.start < self.end && line.is_none() {
+            let byte = self.input[self.start];
+            self.start += 1;
+            line = self.console.feed(byte, &mut echo).await?;
+        }
 
-        info!("{:?}", self.buf.as_str());
+        Ok(self.console.finish(line, &mut echo).await?)
+    }
+}
 
-        self.buf.push_str(input_string).unwrap();
+pub(crate) type Echo = Vec<u8, ECHO_BUF_LEN>;
 
-        let newline_loc = self.buf.find("\r");
-        if let Some(loc) = newline_loc {
-            let mut output = String::new();
-            output.push_str(&self.buf.as_str()[0..loc]).unwrap();
+/// The terminal side of an [`InteractiveTransport`], fed a byte at a time.
+///
+/// Echo is collected by the caller and written out when it fills up, so a
+/// burst of input goes out in few writes.
+pub(crate) struct Console<W: UarteTx, C: Completer, const H: usize> {
+    editor: LineEditor<MAX_LINE_LEN, H>,
+    prompted: bool,
+    tx: W,
+    completer: C,
+}
 
-            let mut tmp = String::new();
-            let buf_size = self.buf.len();
-            tmp.push_str(&self.buf.as_str()[loc + 1..buf_size]).unwrap();
-            self.buf = tmp;
-            return Ok(Some(output));
+impl<W: UarteTx, C: Completer, const H: usize> Console<W, C, H> {
+    pub(crate) fn new(tx: W, completer: C) -> Self {
+        Self {
+            editor: LineEditor::new("> "),
+            prompted: false,
+            tx,
+            completer,
         }
+    }
+
+    /// Adds the prompt to `echo` unless it is already showing.
+    pub(crate) fn prompt(&mut self, echo: &mut Echo) {
+        if !self.prompted {
+            self.editor.write_prompt(echo);
+            self.prompted = true;
+        }
+    }
 
+    /// Feeds one byte, returning the line once it is entered.
+    pub(crate) async fn feed(
+        &mut self,
+        byte: u8,
+        echo: &mut Echo,
+    ) -> Result<Option<String<MAX_LINE_LEN>>, UarteTxError> {
+        if echo.capacity() - echo.len() < echo_capacity(MAX_LINE_LEN) {
+            self.flush(echo).await?;
+        }
+        match self.editor.feed(byte, echo) {
+            Some(Edit::Line(line)) => {
+                self.prompted = false;
+                return Ok(Some(line));
+            }
+            Some(Edit::Complete) => {
+                self.flush(echo).await?;
+                self.complete(echo).await?;
+            }
+            None => {}
+        }
         Ok(None)
     }
+
+    /// Writes out `echo` and passes `line` on, unless it is
+    /// [`HISTORY_COMMAND`], which is answered here.
+    pub(crate) async fn finish(
+        &mut self,
+        line: Option<String<MAX_LINE_LEN>>,
+        echo: &mut Echo,
+    ) -> Result<Option<String<MAX_LINE_LEN>>, UarteTxError> {
+        self.flush(echo).await?;
+
+        if line.as_ref().is_some_and(|l| l.trim() == HISTORY_COMMAND) {
+            self.write_history().await?;
+            return Ok(None);
+        }
+
+        Ok(line)
+    }
+
+    /// Drops the line being typed, showing a fresh prompt.
+    pub(crate) fn cancel(&mut self, echo: &mut Echo) {
+        self.editor.cancel(echo);
+        self.prompted = true;
+    }
+
+    pub(crate) async fn flush(&mut self, echo: &mut Echo) -> Result<(), UarteTxError> {
+        flush(&mut self.tx, echo).await
+    }
+
+    async fn complete(&mut self, echo: &mut Echo) -> Result<(), UarteTxError> {
+        let mut candidates: Vec<&'static str, MAX_COMPLETIONS> = Vec::new();
+        let line = self.editor.before_cursor();
+        let word_start = self.completer.complete(line, &mut candidates).await;
+        if word_start == 0 && HISTORY_COMMAND.starts_with(line) {
+            let _ = candidates.push(HISTORY_COMMAND);
+        }
+
+        if self.editor.complete(word_start, &candidates, echo) {
+            return Ok(());
+        }
+
+        let _ = echo.extend_from_slice(b"\r\n");
+        for candidate in candidates {
+            if echo.capacity() - echo.len() < candidate.len() + 2 {
+                flush(&mut self.tx, echo).await?;
+            }
+            let _ = echo.extend_from_slice(candidate.as_bytes());
+            let _ = echo.extend_from_slice(b"  ");
+        }
+        flush(&mut self.tx, echo).await?;
+        let _ = echo.extend_from_slice(b"\r\n");
+        self.editor.redraw(echo);
+        Ok(())
+    }
+
+    async fn write_history(&mut self) -> Result<(), UarteTxError> {
+        let mut echo: Echo = Vec::new();
+        for (index, line) in self.editor.history().iter().enumerate() {
+            let mut entry: String<{ MAX_LINE_LEN + 8 }> = String::new();
+            let _ = write!(entry, "{:>4}  {}\r\n", index + 1, line);
+            if echo.extend_from_slice(entry.as_bytes()).is_err() {
+                flush(&mut self.tx, &mut echo).await?;
+                let _ = echo.extend_from_slice(entry.as_bytes());
+            }
+        }
+        flush(&mut self.tx, &mut echo).await
+    }
+}
+
+/// Reads from `rx` until idle, or returns `None` after logging a line
+/// error, so the caller can drop the partial line and carry on.
+pub(crate) async fn read_recovering<R: UarteRx>(
+    rx: &mut R,
+    buffer: &mut [u8],
+) -> Result<Option<usize>, UarteRxError> {
+    match rx.read_until_idle(buffer).await {
+        Ok(size) => Ok(Some(size)),
+        Err(error) if error.is_line_error() => {
+            warn!("dropped partial line: {}", error);
+            Ok(None)
+        }
+        Err(error) => Err(error),
+    }
+}
+
+async fn flush<W: UarteTx>(tx: &mut W, echo: &mut Echo) -> Result<(), UarteTxError> {
+    if !echo.is_empty() {
+        tx.write(echo).await?;
+        echo.clear();
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod test {
+    use std::sync::{Arc, Mutex};
+
     use crate::uarte::MockUarteRx;
+    use crate::uarte_mock::capture_tx;
 
     use super::*;
 
@@ -122,4 +514,392 @@ mod test {
         let expected_string = "String";
         assert_eq!(expected_string, output_string.as_str());
     }
+
+    fn reading(chunks: &'static [&'static [u8]]) -> MockUarteRx {
+        let mut mock = MockUarteRx::new();
+        for chunk in chunks {
+            mock.expect_read_until_idle().times(1).returning(|buf| {
+                buf[..chunk.len()].copy_from_slice(chunk);
+                Ok(chunk.len())
+            });
+        }
+        mock
+    }
+
+    fn drained<T: UarteRx>(
+        transport: &mut UartTransport<T>,
+    ) -> std::vec::Vec<String<MAX_LINE_LEN>> {
+        transport.drain_lines().map(Result::unwrap).collect()
+    }
+
+    #[futures_test::test]
+    async fn test_next_line_with_lf_and_crlf() {
+        let mut transport = UartTransport::new(reading(&[b"one\ntwo\r", b"\nthree\r\n"]));
+
+        assert_eq!(transport.next_line().await.unwrap().unwrap(), "one");
+        assert_eq!(transport.drain_lines().next().unwrap().unwrap(), "two");
+        // The `\n` of the split `\r\n` doesn't make an empty line.
+        assert_eq!(transport.next_line().await.unwrap().unwrap(), "three");
+        assert_eq!(transport.drain_lines().count(), 0);
+    }
+
+    #[futures_test::test]
+    async fn test_next_line_with_configured_ending() {
+        let mut transport =
+            UartTransport::new(reading(&[b"a\rb\r\nc\n"])).with_line_ending(LineEnding::Lf);
+        assert_eq!(transport.next_line().await.unwrap().unwrap(), "a\rb");
+        assert_eq!(drained(&mut transport), ["c"]);
+
+        let mut transport =
+            UartTransport::new(reading(&[b"a\nb\r\nc\r"])).with_line_ending(LineEnding::Cr);
+        assert_eq!(transport.next_line().await.unwrap().unwrap(), "a\nb");
+        assert_eq!(drained(&mut transport), ["c"]);
+    }
+
+    #[futures_test::test]
+    async fn test_drain_lines() {
+        let mut transport = UartTransport::new(reading(&[b"one\r\ntwo\r\n\r\nthree\r\nfou"]));
+
+        assert_eq!(transport.next_line().await.unwrap().unwrap(), "one");
+        assert_eq!(drained(&mut transport), ["two", "", "three"]);
+        assert_eq!(transport.drain_lines().count(), 0);
+    }
+
+    #[futures_test::test]
+    async fn test_next_line_errors_and_recovers() {
+        let mut long = [b'a'; 300].to_vec();
+        long.extend(b"\rnext\r\xff\xfe\rok\r");
+        let long: &'static [u8] = long.leak();
+        let chunks = long.chunks(64).collect::<std::vec::Vec<_>>().leak();
+        let mut transport = UartTransport::new(reading(chunks));
+
+        for _ in 0..4 {
+            assert!(transport.next_line().await.unwrap().is_none());
+        }
+        assert!(matches!(
+            transport.next_line().await,
+            Err(UartTransportError::LineOverflow)
+        ));
+        let lines: std::vec::Vec<_> = transport.drain_lines().collect();
+        assert!(matches!(
+            lines.as_slice(),
+            [Ok(next), Err(UartTransportError::InvalidUtf8), Ok(ok)] if next == "next" && ok == "ok"
+        ));
+    }
+
+    #[futures_test::test]
+    async fn test_next_line_drops_line_on_rx_error() {
+        let mut mock = MockUarteRx::new();
+        mock.expect_read_until_idle().times(1).returning(|buf| {
+            buf[..4].copy_from_slice(b"garb");
+            Ok(4)
+        });
+        mock.expect_read_until_idle()
+            .times(1)
+            .returning(|_| Err(UarteRxError::Framing));
+        mock.expect_read_until_idle().times(1).returning(|buf| {
+            buf[..3].copy_from_slice(b"ok\r");
+            Ok(3)
+        });
+        mock.expect_read_until_idle()
+            .times(1)
+            .returning(|_| Err(UarteRxError::BufferNotInRam));
+        let mut transport = UartTransport::new(mock);
+
+        assert!(transport.next_line().await.unwrap().is_none());
+        assert!(transport.next_line().await.unwrap().is_none());
+        let line = transport.next_line().await.unwrap().unwrap();
+        assert_eq!(line.as_str(), "ok");
+        assert!(matches!(
+            transport.next_line().await,
+            Err(UartTransportError::Rx(UarteRxError::BufferNotInRam))
+        ));
+    }
+
+    #[futures_test::test]
+    async fn test_next_line_with_split_character() {
+        let mut transport = UartTransport::new(reading(&[b"caf\xc3", b"\xa9\r"]));
+
+        assert!(transport.next_line().await.unwrap().is_none());
+        assert_eq!(transport.next_line().await.unwrap().unwrap(), "café");
+    }
+
+    /// A xorshift generator, so the fuzz tests are repeatable.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    /// Hands `stream` out in reads of random length, then reads nothing.
+    fn reading_randomly(
+        stream: std::vec::Vec<u8>,
+        mut rng: Rng,
+    ) -> (MockUarteRx, Arc<Mutex<usize>>) {
+        let left = Arc::new(Mutex::new(stream.len()));
+        let mut stream = std::collections::VecDeque::from(stream);
+        let mut mock = MockUarteRx::new();
+        let remaining = left.clone();
+        mock.expect_read_until_idle().returning(move |buf| {
+            let len = (rng.below(64) + 1).min(buf.len()).min(stream.len());
+            for (dst, src) in buf.iter_mut().zip(stream.drain(..len)) {
+                *dst = src;
+            }
+            *remaining.lock().unwrap() = stream.len();
+            Ok(len)
+        });
+        (mock, left)
+    }
+
+    /// Reads lines until the stream behind `transport` has run out.
+    async fn read_all<T: UarteRx>(
+        transport: &mut UartTransport<T>,
+        left: Arc<Mutex<usize>>,
+    ) -> std::vec::Vec<Result<String<MAX_LINE_LEN>, UartTransportError>> {
+        let mut lines = std::vec::Vec::new();
+        loop {
+            match transport.next_line().await.transpose() {
+                Some(line) => lines.push(line),
+                None if *left.lock().unwrap() == 0 => return lines,
+                None => {}
+            }
+        }
+    }
+
+    #[futures_test::test]
+    async fn test_fuzz_random_bytes() {
+        for seed in 1..=50 {
+            let mut rng = Rng(seed);
+            let len = rng.below(2000);
+            // Every other stream has lines long enough to overflow.
+            let line_len = if seed % 2 == 0 { 20 } else { 400 };
+            let mut stream: std::vec::Vec<u8> = (0..len)
+                .map(|_| match rng.below(line_len) {
+                    0 => b'\r',
+                    1 => b'\n',
+                    _ => rng.below(256) as u8,
+                })
+                .collect();
+            stream.extend(b"\rOK\r");
+
+            let (rx, left) = reading_randomly(stream, Rng(seed));
+            let mut transport = UartTransport::new(rx);
+            let lines = read_all(&mut transport, left).await;
+
+            for line in lines.iter().flatten() {
+                assert!(!line.contains(['\r', '\n']), "seed {seed}: {line:?}");
+            }
+            assert!(
+                matches!(lines.last(), Some(Ok(line)) if line == "OK"),
+                "seed {seed}"
+            );
+        }
+    }
+
+    #[futures_test::test]
+    async fn test_fuzz_lines_split_across_reads() {
+        const CHARS: [char; 6] = ['a', 'Z', ' ', 'é', '€', '😀'];
+        const ENDINGS: [&str; 3] = ["\r", "\n", "\r\n"];
+
+        for seed in 1..=50 {
+            let mut rng = Rng(seed);
+            let mut expected = std::vec::Vec::new();
+            let mut stream = std::string::String::new();
+            for _ in 0..rng.below(20) {
+                let line: std::string::String =
+                    (0..=rng.below(60)).map(|_| CHARS[rng.below(6)]).collect();
+                stream += &line;
+                stream += ENDINGS[rng.below(3)];
+                expected.push(line);
+            }
+
+            let (rx, left) = reading_randomly(stream.into_bytes(), Rng(seed));
+            let mut transport = UartTransport::new(rx);
+            let lines: std::vec::Vec<_> = read_all(&mut transport, left)
+                .await
+                .into_iter()
+                .map(|line| line.unwrap().as_str().to_owned())
+                .collect();
+            assert_eq!(lines, expected, "seed {seed}");
+        }
+    }
+
+    #[futures_test::test]
+    async fn test_writer_lines_and_chunks() {
+        let (tx, written) = capture_tx();
+        let mut console = UartWriter::new(tx);
+
+        console.write_line("ready").await.unwrap();
+        console.write(&[b'x'; 100]).await.unwrap();
+
+        let writes = written.writes();
+        assert_eq!(writes[..2], [b"ready".to_vec(), b"\r\n".to_vec()]);
+        assert_eq!(writes[2..], [vec![b'x'; 64], vec![b'x'; 36]]);
+    }
+
+    #[futures_test::test]
+    async fn test_writer_formats() {
+        let (tx, written) = capture_tx();
+        let mut console = UartWriter::new(tx);
+
+        let fps = 60;
+        writeln!(console, "frame rate {}", fps).await.unwrap();
+        write!(console, "a\r\nb\n").await.unwrap();
+        assert_eq!(written.text(), "frame rate 60\r\na\r\nb\r\n");
+        written.clear();
+
+        // Longer than a chunk, with a line ending across the boundary.
+        let long = format!("{}\n{}", "y".repeat(63), "z".repeat(70));
+        write!(console, "{}", long).await.unwrap();
+        let writes = written.writes();
+        assert!(writes.iter().all(|w| w.len() <= WRITE_CHUNK_LEN));
+        assert_eq!(written.text(), long.replace('\n', "\r\n"));
+        written.clear();
+
+        write!(console, "{}", "w".repeat(MAX_FMT_LEN + 10))
+            .await
+            .unwrap();
+        assert_eq!(written.text(), "w".repeat(MAX_FMT_LEN));
+    }
+
+    #[futures_test::test]
+    async fn test_interactive_echoes_and_edits() {
+        let mut mock = MockUarteRx::new();
+
+        let input = b"scrp\x7fol\x1b[Dl\r";
+        mock.expect_read_until_idle().times(1).returning(|buf| {
+            buf[..input.len()].copy_from_slice(input);
+            Ok(input.len())
+        });
+
+        let (tx, written) = capture_tx();
+        let mut transport: InteractiveTransport<_, _, _, 4> =
+            InteractiveTransport::new(mock, tx, ());
+
+        let output_string = transport.next_line().await.unwrap().unwrap();
+        assert_eq!("scroll", output_string.as_str());
+
+        assert_eq!(
+            written.text(),
+            "> scrp\x08\x1b[Kol\x1b[1Dll\x1b[K\x1b[1D\r\n"
+        );
+    }
+
+    #[futures_test::test]
+    async fn test_interactive_keeps_bytes_after_line() {
+        let mut mock = MockUarteRx::new();
+
+        let input = b"one\rtwo\r";
+        mock.expect_read_until_idle().times(1).returning(|buf| {
+            buf[..input.len()].copy_from_slice(input);
+            Ok(input.len())
+        });
+
+        let (tx, written) = capture_tx();
+        let mut transport: InteractiveTransport<_, _, _, 4> =
+            InteractiveTransport::new(mock, tx, ());
+
+        let output_string = transport.next_line().await.unwrap().unwrap();
+        assert_eq!("one", output_string.as_str());
+        let output_string = transport.next_line().await.unwrap().unwrap();
+        assert_eq!("two", output_string.as_str());
+
+        assert_eq!(written.text(), "> one\r\n> two\r\n");
+    }
+
+    #[futures_test::test]
+    async fn test_interactive_cancels_line_on_rx_error() {
+        let mut mock = MockUarteRx::new();
+        mock.expect_read_until_idle().times(1).returning(|buf| {
+            buf[..3].copy_from_slice(b"abc");
+            Ok(3)
+        });
+        mock.expect_read_until_idle()
+            .times(1)
+            .returning(|_| Err(UarteRxError::Overrun));
+        mock.expect_read_until_idle().times(1).returning(|buf| {
+            buf[..2].copy_from_slice(b"x\r");
+            Ok(2)
+        });
+
+        let (tx, written) = capture_tx();
+        let mut transport: InteractiveTransport<_, _, _, 4> =
+            InteractiveTransport::new(mock, tx, ());
+
+        assert!(transport.next_line().await.unwrap().is_none());
+        assert!(transport.next_line().await.unwrap().is_none());
+        let output_string = transport.next_line().await.unwrap().unwrap();
+        assert_eq!("x", output_string.as_str());
+
+        assert_eq!(written.text(), "> abc^C\r\n> x\r\n");
+    }
+
+    #[futures_test::test]
+    async fn test_interactive_history_command() {
+        let mut mock = MockUarteRx::new();
+
+        let input = b"one\rtwo\r!history\r\x1b[A\x1b[A\r";
+        mock.expect_read_until_idle().times(1).returning(|buf| {
+            buf[..input.len()].copy_from_slice(input);
+            Ok(input.len())
+        });
+
+        let (tx, written) = capture_tx();
+        let mut transport: InteractiveTransport<_, _, _, 4> =
+            InteractiveTransport::new(mock, tx, ());
+
+        transport.next_line().await.unwrap().unwrap();
+        transport.next_line().await.unwrap().unwrap();
+        written.clear();
+
+        assert!(transport.next_line().await.unwrap().is_none());
+        assert_eq!(
+            written.text(),
+            "> !history\r\n   1  one\r\n   2  two\r\n   3  !history\r\n"
+        );
+
+        let output_string = transport.next_line().await.unwrap().unwrap();
+        assert_eq!("two", output_string.as_str());
+    }
+
+    struct FixedCompleter(&'static [&'static str]);
+
+    impl Completer for FixedCompleter {
+        async fn complete(
+            &self,
+            line: &str,
+            candidates: &mut heapless::Vec<&'static str, MAX_COMPLETIONS>,
+        ) -> usize {
+            for candidate in self.0.iter().filter(|c| c.starts_with(line)) {
+                candidates.push(candidate).unwrap();
+            }
+            0
+        }
+    }
+
+    #[futures_test::test]
+    async fn test_interactive_tab_completion() {
+        let mut mock = MockUarteRx::new();
+
+        let input = b"s\th\t\r";
+        mock.expect_read_until_idle().times(1).returning(|buf| {
+            buf[..input.len()].copy_from_slice(input);
+            Ok(input.len())
+        });
+
+        let (tx, written) = capture_tx();
+        let completer = FixedCompleter(&["scroll", "shell", "help"]);
+        let mut transport: InteractiveTransport<_, _, _, 4> =
+            InteractiveTransport::new(mock, tx, completer);
+
+        let output_string = transport.next_line().await.unwrap().unwrap();
+        assert_eq!("shell ", output_string.as_str());
+
+        assert_eq!(written.text(), "> s\r\nscroll  shell  \r\n> shell \r\n");
+    }
 }
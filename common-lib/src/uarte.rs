@@ -1,5 +1,7 @@
 use embassy_nrf::timer::{self};
 use embassy_nrf::uarte;
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::mutex::Mutex;
 use thiserror::Error;
 
 mod nrf {
@@ -7,10 +9,27 @@ mod nrf {
     pub use embassy_nrf::uarte::UarteTx;
 }
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(not(any(test, feature = "host")), derive(defmt::Format))]
 pub enum UarteTxError {
-    #[error("")]
-    Error,
+    #[error("buffer too long")]
+    BufferTooLong,
+    #[error("buffer not in RAM")]
+    BufferNotInRam,
+    #[error("timed out")]
+    Timeout,
+    #[error("transmit failed")]
+    Other,
+}
+
+impl From<uarte::Error> for UarteTxError {
+    fn from(error: uarte::Error) -> Self {
+        match error {
+            uarte::Error::BufferTooLong => UarteTxError::BufferTooLong,
+            uarte::Error::BufferNotInRAM => UarteTxError::BufferNotInRam,
+            _ => UarteTxError::Other,
+        }
+    }
 }
 
 #[cfg_attr(test, mockall::automock)]
@@ -21,23 +40,77 @@ pub trait UarteTx {
 
 impl<'d, T: uarte::Instance> UarteTx for nrf::UarteTx<'d, T> {
     async fn write(&mut self, buffer: &[u8]) -> Result<(), UarteTxError> {
-        match self.write(buffer).await {
-            Ok(()) => Ok(()),
-            Err(_) => Err(UarteTxError::Error),
-        }
+        Ok(self.write(buffer).await?)
     }
     async fn write_from_ram(&mut self, buffer: &[u8]) -> Result<(), UarteTxError> {
-        match self.write_from_ram(buffer).await {
-            Ok(()) => Ok(()),
-            Err(_) => Err(UarteTxError::Error),
-        }
+        Ok(self.write_from_ram(buffer).await?)
+    }
+}
+
+/// Lets several users within an executor share one transmitter.
+impl<M: RawMutex, T: UarteTx> UarteTx for &Mutex<M, T> {
+    async fn write(&mut self, buffer: &[u8]) -> Result<(), UarteTxError> {
+        self.lock().await.write(buffer).await
+    }
+    async fn write_from_ram(&mut self, buffer: &[u8]) -> Result<(), UarteTxError> {
+        self.lock().await.write_from_ram(buffer).await
     }
 }
 
-#[derive(Error, Debug)]
+impl<T: UarteTx> UarteTx for &mut T {
+    async fn write(&mut self, buffer: &[u8]) -> Result<(), UarteTxError> {
+        (**self).write(buffer).await
+    }
+    async fn write_from_ram(&mut self, buffer: &[u8]) -> Result<(), UarteTxError> {
+        (**self).write_from_ram(buffer).await
+    }
+}
+
+#[derive(Error, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(not(any(test, feature = "host")), derive(defmt::Format))]
 pub enum UarteRxError {
-    #[error("")]
-    Error,
+    #[error("buffer too long")]
+    BufferTooLong,
+    #[error("buffer not in RAM")]
+    BufferNotInRam,
+    #[error("framing error")]
+    Framing,
+    #[error("parity error")]
+    Parity,
+    #[error("overrun")]
+    Overrun,
+    #[error("break condition")]
+    Break,
+    #[error("receive failed")]
+    Other,
+}
+
+impl UarteRxError {
+    /// Whether the error came from the line, losing some input, so that
+    /// reading can carry on.
+    pub fn is_line_error(&self) -> bool {
+        matches!(
+            self,
+            UarteRxError::Framing
+                | UarteRxError::Parity
+                | UarteRxError::Overrun
+                | UarteRxError::Break
+        )
+    }
+}
+
+impl From<uarte::Error> for UarteRxError {
+    fn from(error: uarte::Error) -> Self {
+        match error {
+            uarte::Error::BufferTooLong => UarteRxError::BufferTooLong,
+            uarte::Error::BufferNotInRAM => UarteRxError::BufferNotInRam,
+            uarte::Error::Framing => UarteRxError::Framing,
+            uarte::Error::Parity => UarteRxError::Parity,
+            uarte::Error::Overrun => UarteRxError::Overrun,
+            uarte::Error::Break => UarteRxError::Break,
+            _ => UarteRxError::Other,
+        }
+    }
 }
 
 #[cfg_attr(test, mockall::automock)]
@@ -48,15 +121,9 @@ pub trait UarteRx {
 
 impl<'d, T: uarte::Instance, U: timer::Instance> UarteRx for nrf::UarteRxWithIdle<'d, T, U> {
     async fn read(&mut self, buffer: &mut [u8]) -> Result<(), UarteRxError> {
-        match self.read(buffer).await {
-            Ok(()) => Ok(()),
-            Err(_) => Err(UarteRxError::Error),
-        }
+        Ok(self.read(buffer).await?)
     }
     async fn read_until_idle(&mut self, buffer: &mut [u8]) -> Result<usize, UarteRxError> {
-        match self.read_until_idle(buffer).await {
-            Ok(size) => Ok(size),
-            Err(_) => Err(UarteRxError::Error),
-        }
+        Ok(self.read_until_idle(buffer).await?)
     }
 }
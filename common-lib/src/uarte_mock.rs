@@ -0,0 +1,43 @@
+use std::sync::{Arc, Mutex};
+
+use crate::uarte::MockUarteTx;
+
+/// What a transmitter from [`capture_tx`] was asked to write.
+#[derive(Clone, Default)]
+pub struct TxCapture(Arc<Mutex<Vec<Vec<u8>>>>);
+
+impl TxCapture {
+    /// Each write, in order.
+    pub fn writes(&self) -> Vec<Vec<u8>> {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn bytes(&self) -> Vec<u8> {
+        self.writes().concat()
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8(self.bytes()).unwrap()
+    }
+
+    pub fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+}
+
+/// A transmitter that accepts every write, and what it was given.
+pub fn capture_tx() -> (MockUarteTx, TxCapture) {
+    let capture = TxCapture::default();
+    let mut mock = MockUarteTx::new();
+    let sink = capture.clone();
+    mock.expect_write().returning(move |buf| {
+        sink.0.lock().unwrap().push(buf.to_vec());
+        Ok(())
+    });
+    let sink = capture.clone();
+    mock.expect_write_from_ram().returning(move |buf| {
+        sink.0.lock().unwrap().push(buf.to_vec());
+        Ok(())
+    });
+    (mock, capture)
+}
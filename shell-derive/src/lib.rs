@@ -0,0 +1,280 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::punctuated::Punctuated;
+use syn::{
+    parse_macro_input, Attribute, Data, DeriveInput, Error, Expr, ExprLit, Fields, Lit, LitStr,
+    Meta, Result, Token, UnOp,
+};
+
+/// Derives `common_lib::cli::ShellCommand` for an enum.
+///
+/// The enum becomes a root command and each variant a subcommand whose
+/// named fields are its arguments, in order. Doc comments become the help
+/// descriptions. Names default to the kebab-cased identifiers.
+///
+/// Attributes, all under `#[shell(..)]`:
+/// - on the enum: `root = "name"` and `backpressure = Reject`
+/// - on a variant: `name = "name"`
+/// - on a field: `min = 0, max = 10` for integers, or
+///   `keywords("left", "right")`, which a `&'static str` must have
+#[proc_macro_derive(ShellCommand, attributes(shell))]
+pub fn derive_shell_command(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: DeriveInput) -> Result<TokenStream2> {
+    let Data::Enum(data) = &input.data else {
+        return Err(Error::new_spanned(
+            &input.ident,
+            "ShellCommand can only be derived for enums",
+        ));
+    };
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &input.generics,
+            "ShellCommand cannot be derived for generic enums",
+        ));
+    }
+
+    let ident = &input.ident;
+    let mut root = kebab_case(&ident.to_string());
+    let mut backpressure = None;
+    for meta in shell_attrs(&input.attrs)? {
+        match &meta {
+            Meta::NameValue(nv) if nv.path.is_ident("root") => root = string_value(&nv.value)?,
+            Meta::NameValue(nv) if nv.path.is_ident("backpressure") => {
+                backpressure = Some(nv.value.clone())
+            }
+            _ => return Err(Error::new_spanned(meta, "unknown shell attribute")),
+        }
+    }
+    let description = doc_string(&input.attrs);
+
+    let mut sub_commands = Vec::new();
+    let mut arms = Vec::new();
+    for variant in &data.variants {
+        let mut name = kebab_case(&variant.ident.to_string());
+        for meta in shell_attrs(&variant.attrs)? {
+            match &meta {
+                Meta::NameValue(nv) if nv.path.is_ident("name") => name = string_value(&nv.value)?,
+                _ => return Err(Error::new_spanned(meta, "unknown shell attribute")),
+            }
+        }
+        let sub_description = doc_string(&variant.attrs);
+        let variant_ident = &variant.ident;
+
+        let fields = match &variant.fields {
+            Fields::Named(fields) => fields.named.iter().collect(),
+            Fields::Unit => Vec::new(),
+            Fields::Unnamed(_) => {
+                return Err(Error::new_spanned(
+                    variant,
+                    "subcommand arguments must be named fields",
+                ))
+            }
+        };
+
+        let mut args = Vec::new();
+        let mut values = Vec::new();
+        for (index, field) in fields.into_iter().enumerate() {
+            let field_ident = field.ident.as_ref().expect("named field");
+            let arg_name = field_ident.to_string();
+            let kind = arg_kind(&field.ty, &field.attrs)?;
+            args.push(quote! {
+                ::common_lib::cli::Arg { name: #arg_name, kind: #kind }
+            });
+            values.push(quote! {
+                #field_ident: command.field(#index, #arg_name)?
+            });
+        }
+
+        sub_commands.push(quote! {
+            ::common_lib::cli::SubCommand {
+                command: #name,
+                description: #sub_description,
+                args: &[#(#args),*],
+                children: &[],
+            }
+        });
+        let value = match &variant.fields {
+            Fields::Unit => quote!(Self::#variant_ident),
+            _ => quote!(Self::#variant_ident { #(#values),* }),
+        };
+        arms.push(quote! {
+            [#name] => ::core::result::Result::Ok(#value),
+        });
+    }
+
+    let backpressure = backpressure.map(|policy| {
+        quote! {
+            const BACKPRESSURE: ::common_lib::cli::Backpressure =
+                ::common_lib::cli::Backpressure::#policy;
+        }
+    });
+
+    Ok(quote! {
+        impl<
+                M: ::common_lib::cli::RawMutex + 'static,
+                const DEPTH: usize,
+                const LINE: usize,
+            > ::common_lib::cli::ShellCommand<M, DEPTH, LINE> for #ident
+        {
+            const ROOT: &'static str = #root;
+            const DESCRIPTION: &'static str = #description;
+            const CHILDREN: &'static [::common_lib::cli::SubCommand] = &[#(#sub_commands),*];
+            #backpressure
+
+            fn from_parsed(
+                command: &::common_lib::cli::ParsedCommand<LINE>,
+            ) -> ::core::result::Result<Self, ::common_lib::cli::ShellError> {
+                match command.path.as_slice() {
+                    #(#arms)*
+                    _ => ::core::result::Result::Err(
+                        ::common_lib::cli::ShellError::UnknownSubcommand,
+                    ),
+                }
+            }
+        }
+    })
+}
+
+/// The `ArgKind` for a field: from its attributes if given, otherwise the
+/// field type's default.
+fn arg_kind(ty: &syn::Type, attrs: &[Attribute]) -> Result<TokenStream2> {
+    let mut min = None;
+    let mut max = None;
+    let mut keywords = None;
+    for meta in shell_attrs(attrs)? {
+        match &meta {
+            Meta::NameValue(nv) if nv.path.is_ident("min") => min = Some(int_value(&nv.value)?),
+            Meta::NameValue(nv) if nv.path.is_ident("max") => max = Some(int_value(&nv.value)?),
+            Meta::List(list) if list.path.is_ident("keywords") => {
+                let words =
+                    list.parse_args_with(Punctuated::<LitStr, Token![,]>::parse_terminated)?;
+                let words = words.iter();
+                keywords = Some(quote!(::common_lib::cli::ArgKind::Keyword(&[#(#words),*])));
+            }
+            _ => return Err(Error::new_spanned(meta, "unknown shell attribute")),
+        }
+    }
+
+    match (min, max, keywords) {
+        (None, None, None) if is_str_ref(ty) => Err(Error::new_spanned(
+            ty,
+            "`&'static str` fields need `#[shell(keywords(..))]`",
+        )),
+        (None, None, None) => Ok(quote!(<#ty as ::common_lib::cli::FromArg>::KIND)),
+        (Some(_), Some(_), None) if !is_int(ty) => Err(Error::new_spanned(
+            ty,
+            "`min` and `max` need an integer field",
+        )),
+        (Some(min), Some(max), None) => Ok(quote!(::common_lib::cli::ArgKind::Int {
+            min: #min,
+            max: #max,
+        })),
+        (None, None, Some(_)) if !is_str_ref(ty) => Err(Error::new_spanned(
+            ty,
+            "`keywords(..)` needs a `&'static str` field",
+        )),
+        (None, None, Some(keywords)) => Ok(keywords),
+        _ => Err(Error::new_spanned(
+            ty,
+            "expected both `min` and `max`, or `keywords(..)` on its own",
+        )),
+    }
+}
+
+/// Whether `ty` is written as a `&str`, which can only hold a keyword.
+fn is_str_ref(ty: &syn::Type) -> bool {
+    match ty {
+        syn::Type::Reference(reference) => match &*reference.elem {
+            syn::Type::Path(path) => path.qself.is_none() && path.path.is_ident("str"),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Whether `ty` is written as one of the integers `FromArg` takes.
+fn is_int(ty: &syn::Type) -> bool {
+    const INTS: [&str; 9] = [
+        "i8", "i16", "i32", "i64", "u8", "u16", "u32", "u64", "usize",
+    ];
+    match ty {
+        syn::Type::Path(path) => {
+            path.qself.is_none() && INTS.iter().any(|int| path.path.is_ident(int))
+        }
+        _ => false,
+    }
+}
+
+fn shell_attrs(attrs: &[Attribute]) -> Result<Vec<Meta>> {
+    let mut metas = Vec::new();
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("shell")) {
+        metas.extend(attr.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)?);
+    }
+    Ok(metas)
+}
+
+fn doc_string(attrs: &[Attribute]) -> String {
+    let lines: Vec<String> = attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("doc"))
+        .filter_map(|attr| match &attr.meta {
+            Meta::NameValue(nv) => match &nv.value {
+                Expr::Lit(ExprLit {
+                    lit: Lit::Str(s), ..
+                }) => Some(s.value().trim().to_owned()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .filter(|line| !line.is_empty())
+        .collect();
+    lines.join(" ")
+}
+
+fn string_value(expr: &Expr) -> Result<String> {
+    match expr {
+        Expr::Lit(ExprLit {
+            lit: Lit::Str(s), ..
+        }) => Ok(s.value()),
+        _ => Err(Error::new_spanned(expr, "expected a string literal")),
+    }
+}
+
+fn int_value(expr: &Expr) -> Result<TokenStream2> {
+    // Accept negative bounds, which parse as a unary minus.
+    match expr {
+        Expr::Lit(ExprLit {
+            lit: Lit::Int(i), ..
+        }) => Ok(quote!(#i)),
+        Expr::Unary(unary) if matches!(unary.op, UnOp::Neg(_)) => {
+            let inner = int_value(&unary.expr)?;
+            Ok(quote!(-#inner))
+        }
+        _ => Err(Error::new_spanned(expr, "expected an integer literal")),
+    }
+}
+
+/// `SetSpeed` becomes `set-speed`.
+fn kebab_case(name: &str) -> String {
+    let mut out = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_ascii_uppercase() {
+            if i > 0 {
+                out.push('-');
+            }
+            out.push(c.to_ascii_lowercase());
+        } else if c == '_' {
+            out.push('-');
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
@@ -0,0 +1,317 @@
+//! Message types and framing for driving the shell over a binary link.
+//!
+//! Each message is postcard-encoded, followed by a little-endian CRC-16
+//! of the encoding, then COBS-encoded between two zero bytes. The device
+//! and host sides share this crate so they always agree on the format.
+//!
+//! The leading zero byte tells a device sharing the link with the text
+//! shell that a frame follows. Decoders skip the empty frame it leaves
+//! after the previous one.
+
+#![cfg_attr(not(test), no_std)]
+
+use crc::{Crc, CRC_16_KERMIT};
+use heapless::{String, Vec};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Longest text a [`Command::Scroll`] can carry, as for a shell argument.
+pub const MAX_TEXT_LEN: usize = 50;
+/// Longest line the shell takes.
+pub const MAX_LINE_LEN: usize = 256;
+/// Room a framed request's line needs for the `#<id> ` the device tags it
+/// with: the `#`, up to ten digits of a `u32` and a space.
+pub const ID_PREFIX_LEN: usize = 12;
+/// Longest line a [`Command::Shell`] can carry, leaving room for the id.
+pub const MAX_SHELL_LINE_LEN: usize = MAX_LINE_LEN - ID_PREFIX_LEN;
+/// Longest line in a [`Reply`], as for the text shell.
+pub const MAX_REPLY_LEN: usize = 128;
+pub const MATRIX_SIZE: usize = 5;
+
+/// Longest postcard encoding of a message plus its CRC.
+pub const MAX_BODY_LEN: usize = MAX_LINE_LEN + 16;
+/// Longest frame on the wire, delimiters included.
+pub const MAX_FRAME_LEN: usize = cobs::max_encoding_length(MAX_BODY_LEN) + 2;
+
+const CRC: Crc<u16> = Crc::<u16>::new(&CRC_16_KERMIT);
+
+/// A command for the device, tagged with an id its replies will carry.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Request {
+    pub id: u32,
+    pub command: Command,
+}
+
+// Messages are encoded one at a time, so the size of the largest variant
+// costs nothing.
+#[allow(clippy::large_enum_variant)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Command {
+    /// Scroll text across the LED matrix.
+    Scroll {
+        text: String<MAX_TEXT_LEN>,
+        direction: Direction,
+    },
+    /// Show a still frame, one byte per row with the leftmost column in
+    /// the highest of the low [`MATRIX_SIZE`] bits.
+    SetFrame { rows: [u8; MATRIX_SIZE] },
+    /// Ask for the device status.
+    Status,
+    /// Any other line the text shell accepts.
+    Shell { line: String<MAX_SHELL_LINE_LEN> },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    /// Right to left.
+    Forward,
+    /// Left to right.
+    Back,
+}
+
+/// Output for the request `id`, or `None` when the request had no id.
+///
+/// A request gets any number of lines, then one [`Reply::Status`] once
+/// the device is done with it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Response {
+    pub id: Option<u32>,
+    pub reply: Reply,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Reply {
+    Line(String<MAX_REPLY_LEN>),
+    Error(String<MAX_REPLY_LEN>),
+    /// The last reply to a request.
+    Status(Status),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    Ok,
+    /// The request failed, as told by an earlier [`Reply::Error`].
+    Error,
+}
+
+#[derive(Error, Debug, Clone, Copy, PartialEq)]
+pub enum CodecError {
+    #[error("buffer too small")]
+    BufferFull,
+    #[error("frame too long")]
+    FrameTooLong,
+    #[error("corrupt framing")]
+    Framing,
+    #[error("checksum mismatch")]
+    Checksum,
+    #[error("malformed message")]
+    Message,
+}
+
+/// Encodes `message` as a frame into `out`, returning the frame's length.
+pub fn encode<T: Serialize>(message: &T, out: &mut [u8]) -> Result<usize, CodecError> {
+    let mut body = [0; MAX_BODY_LEN];
+    let len = postcard::to_slice(message, &mut body[..MAX_BODY_LEN - 2])
+        .map_err(|_| CodecError::FrameTooLong)?
+        .len();
+    let crc = CRC.checksum(&body[..len]);
+    body[len..len + 2].copy_from_slice(&crc.to_le_bytes());
+
+    let end = out
+        .len()
+        .checked_sub(1)
+        .filter(|&end| end > 0)
+        .ok_or(CodecError::BufferFull)?;
+    out[0] = 0;
+    let encoded =
+        cobs::try_encode(&body[..len + 2], &mut out[1..end]).map_err(|_| CodecError::BufferFull)?;
+    out[1 + encoded] = 0;
+    Ok(encoded + 2)
+}
+
+/// Decodes a frame, with or without its delimiters, in place.
+pub fn decode<T: DeserializeOwned>(frame: &mut [u8]) -> Result<T, CodecError> {
+    let frame = match frame.split_first_mut() {
+        Some((0, rest)) => rest,
+        _ => frame,
+    };
+    let frame = match frame.split_last_mut() {
+        Some((0, rest)) => rest,
+        _ => frame,
+    };
+    let len = cobs::decode_in_place(frame).map_err(|_| CodecError::Framing)?;
+    if len < 2 {
+        return Err(CodecError::Framing);
+    }
+    let (body, crc) = frame[..len].split_at(len - 2);
+    if CRC.checksum(body).to_le_bytes() != crc {
+        return Err(CodecError::Checksum);
+    }
+    postcard::from_bytes(body).map_err(|_| CodecError::Message)
+}
+
+/// Collects bytes from a stream into frames of up to `N` bytes.
+///
+/// A frame that overflows is reported once its delimiter arrives, so the
+/// decoder resynchronises on the next frame.
+pub struct FrameDecoder<const N: usize = MAX_FRAME_LEN> {
+    buf: Vec<u8, N>,
+    overflowed: bool,
+}
+
+impl<const N: usize> Default for FrameDecoder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> FrameDecoder<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            overflowed: false,
+        }
+    }
+
+    /// Whether no part of a frame has arrived since the last delimiter.
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty() && !self.overflowed
+    }
+
+    /// Drops any part of a frame received so far.
+    pub fn reset(&mut self) {
+        self.buf.clear();
+        self.overflowed = false;
+    }
+
+    /// Feeds one byte, returning the message once a frame is complete.
+    /// Empty frames are skipped.
+    pub fn push<T: DeserializeOwned>(&mut self, byte: u8) -> Option<Result<T, CodecError>> {
+        if byte != 0 {
+            if self.buf.push(byte).is_err() {
+                self.overflowed = true;
+            }
+            return None;
+        }
+
+        let result = match (self.overflowed, self.buf.is_empty()) {
+            (true, _) => Some(Err(CodecError::FrameTooLong)),
+            (false, true) => None,
+            (false, false) => Some(decode(&mut self.buf)),
+        };
+        self.reset();
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(id: u32, command: Command) -> Request {
+        Request { id, command }
+    }
+
+    fn frame<T: Serialize>(message: &T) -> std::vec::Vec<u8> {
+        let mut out = [0; MAX_FRAME_LEN];
+        let len = encode(message, &mut out).unwrap();
+        out[..len].to_vec()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let requests = [
+            request(
+                1,
+                Command::Scroll {
+                    text: String::try_from("HELLO WORLD").unwrap(),
+                    direction: Direction::Back,
+                },
+            ),
+            request(
+                2,
+                Command::SetFrame {
+                    rows: [0x1f, 0x00, 0x11, 0x00, 0x1f],
+                },
+            ),
+            request(u32::MAX, Command::Status),
+            request(
+                4,
+                Command::Shell {
+                    line: String::try_from("x".repeat(MAX_SHELL_LINE_LEN).as_str()).unwrap(),
+                },
+            ),
+        ];
+        for request in requests {
+            let mut encoded = frame(&request);
+            assert_eq!(encoded.first(), Some(&0));
+            assert_eq!(encoded.last(), Some(&0));
+            assert!(!encoded[1..encoded.len() - 1].contains(&0));
+            assert_eq!(decode::<Request>(&mut encoded), Ok(request));
+        }
+
+        let responses = [
+            Response {
+                id: Some(7),
+                reply: Reply::Error(String::try_from("unknown command").unwrap()),
+            },
+            Response {
+                id: Some(7),
+                reply: Reply::Status(Status::Error),
+            },
+        ];
+        for response in responses {
+            assert_eq!(decode::<Response>(&mut frame(&response)), Ok(response));
+        }
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let mut encoded = frame(&request(1, Command::Status));
+        encoded[2] ^= 0x40;
+        assert_eq!(decode::<Request>(&mut encoded), Err(CodecError::Checksum));
+
+        // The code byte claims more data than the frame holds.
+        assert_eq!(
+            decode::<Request>(&mut [0x05, 0x01, 0x00]),
+            Err(CodecError::Framing)
+        );
+
+        // A valid frame holding a response isn't a request.
+        let response = Response {
+            id: None,
+            reply: Reply::Line(String::new()),
+        };
+        assert_eq!(
+            decode::<Request>(&mut frame(&response)),
+            Err(CodecError::Message)
+        );
+
+        let mut small = [0; 4];
+        assert_eq!(
+            encode(&request(1, Command::Status), &mut small),
+            Err(CodecError::BufferFull)
+        );
+    }
+
+    #[test]
+    fn test_frame_decoder_resynchronises() {
+        let first = request(1, Command::Status);
+        let second = request(2, Command::Status);
+
+        let mut stream = vec![0, 0];
+        stream.extend(frame(&first));
+        stream.extend([0x42; 40]);
+        stream.push(0);
+        stream.extend(frame(&second));
+
+        let mut decoder: FrameDecoder<32> = FrameDecoder::new();
+        let messages: std::vec::Vec<Result<Request, CodecError>> =
+            stream.iter().filter_map(|&b| decoder.push(b)).collect();
+        assert_eq!(
+            messages,
+            [Ok(first), Err(CodecError::FrameTooLong), Ok(second)]
+        );
+    }
+}